@@ -0,0 +1,304 @@
+use crate::files::{self, Input, Output};
+use crate::policy::ErrorHandler;
+use crate::report::Stats;
+use crate::tags::BarcodeTags;
+use memchr::memchr;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+/// First bytes of (decompressed) BAM data.
+pub const MAGIC: [u8; 4] = *b"BAM\x01";
+
+/// Size of the block_size field and the fixed-length fields of an alignment record.
+const FIXED_BYTES: usize = 36;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn truncated(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("truncated BAM {what}"),
+    )
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Append exactly `n` bytes read from `reader` to `buf`, returning an error
+/// naming `what` was being read if the input ends before that.
+fn read_to<R: Read>(reader: &mut R, buf: &mut Vec<u8>, n: u64, what: &str) -> io::Result<()> {
+    let read = reader.take(n).read_to_end(buf)?;
+    if read as u64 != n {
+        return Err(truncated(what));
+    }
+    Ok(())
+}
+
+/// Read the BAM header (magic, SAM header text and reference sequences) as it is.
+pub fn read_header<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    read_to(reader, &mut header, 8, "header")?;
+    if header[..4] != MAGIC {
+        return Err(invalid(
+            "invalid BAM header: missing magic bytes".to_string(),
+        ));
+    }
+    let l_text = u32_at(&header, 4);
+    read_to(reader, &mut header, l_text as u64 + 4, "header")?;
+    let n_ref = u32_at(&header, header.len() - 4);
+    for _ in 0..n_ref {
+        read_to(reader, &mut header, 4, "header")?;
+        let l_name = u32_at(&header, header.len() - 4);
+        read_to(reader, &mut header, l_name as u64 + 4, "header")?;
+    }
+    Ok(header)
+}
+
+/// Read the next alignment record (including its block_size field) into record,
+/// erasing previous contents. Returns false on EOF, and an error if the record is truncated.
+pub fn read_record<R: Read>(reader: &mut R, record: &mut Vec<u8>) -> io::Result<bool> {
+    record.clear();
+    reader.take(4).read_to_end(record)?;
+    if record.is_empty() {
+        return Ok(false);
+    }
+    if record.len() < 4 {
+        return Err(truncated("record"));
+    }
+    let block_size = u32_at(record, 0);
+    read_to(reader, record, block_size as u64, "record")?;
+    Ok(true)
+}
+
+/// The read name of a record, for error messages.
+fn read_name(record: &[u8]) -> &[u8] {
+    let name = record.get(FIXED_BYTES..).unwrap_or_default();
+    &name[..memchr(0, name).unwrap_or(name.len())]
+}
+
+/// Offset of the optional tags of a record, after the read name, CIGAR, sequence and qualities.
+fn tags_offset(record: &[u8]) -> io::Result<usize> {
+    if record.len() < FIXED_BYTES {
+        return Err(invalid(format!(
+            "invalid BAM record: {} bytes is too short",
+            record.len()
+        )));
+    }
+    let l_read_name = record[12] as usize;
+    let n_cigar_op = u16::from_le_bytes([record[16], record[17]]) as usize;
+    let l_seq = u32_at(record, 20) as usize;
+    let offset = FIXED_BYTES + l_read_name + 4 * n_cigar_op + l_seq.div_ceil(2) + l_seq;
+    if offset > record.len() {
+        return Err(invalid(
+            "invalid BAM record: fields extend past the end of the record".to_string(),
+        ));
+    }
+    Ok(offset)
+}
+
+/// Find the values (excluding the terminating NUL) of the barcode tags of a record,
+/// whose tags start at `offset`. Returns an error if the tags cannot be parsed,
+/// or one of the barcode tags is not a string.
+fn barcode_tags(record: &[u8], offset: usize) -> io::Result<BarcodeTags> {
+    let mut tags = BarcodeTags::default();
+    let mut i = offset;
+    while i < record.len() {
+        let truncated_tag = || invalid("invalid BAM record: truncated tag".to_string());
+        if i + 3 > record.len() {
+            return Err(truncated_tag());
+        }
+        let (name, tag_type, value) = (&record[i..i + 2], record[i + 2], i + 3);
+        let end = match tag_type {
+            b'A' | b'c' | b'C' => value + 1,
+            b's' | b'S' => value + 2,
+            b'i' | b'I' | b'f' => value + 4,
+            b'Z' | b'H' => value + memchr(0, &record[value..]).ok_or_else(truncated_tag)? + 1,
+            b'B' => {
+                if value + 5 > record.len() {
+                    return Err(truncated_tag());
+                }
+                let size = match record[value] {
+                    b'c' | b'C' => 1,
+                    b's' | b'S' => 2,
+                    b'i' | b'I' | b'f' => 4,
+                    t => {
+                        return Err(invalid(format!(
+                            "invalid BAM record: unknown array type '{}' of tag {}",
+                            t as char,
+                            String::from_utf8_lossy(name)
+                        )))
+                    }
+                };
+                value + 5 + size * u32_at(record, value + 1) as usize
+            }
+            t => {
+                return Err(invalid(format!(
+                    "invalid BAM record: unknown type '{}' of tag {}",
+                    t as char,
+                    String::from_utf8_lossy(name)
+                )))
+            }
+        };
+        if end > record.len() {
+            return Err(truncated_tag());
+        }
+        if let Some(value_range) = tags.get_mut(name) {
+            if tag_type != b'Z' {
+                return Err(invalid(format!(
+                    "invalid BAM record: tag {} is not a string",
+                    String::from_utf8_lossy(name)
+                )));
+            }
+            *value_range = Some(value..end - 1);
+        }
+        i = end;
+    }
+    Ok(tags)
+}
+
+/// Reverse-complement the i5 in the barcode tags of a record in place, and reverse its qualities
+/// (see [`BarcodeTags::rewrite_i5`]). Returns the ranges of i7 and i5 in the record,
+/// or an error (leaving the record unchanged) if it cannot be rewritten.
+pub fn fix_record(record: &mut [u8]) -> io::Result<(Range<usize>, Range<usize>)> {
+    barcode_tags(record, tags_offset(record)?)?
+        .rewrite_i5(record)
+        .map_err(|e| invalid(format!("invalid BAM record: {e}")))
+}
+
+/// Label an error with the input name and the number and read name of the record.
+fn record_error(name: &str, record_number: u64, record: &[u8], e: io::Error) -> io::Error {
+    let position = format!(
+        "record {record_number} ('{}')",
+        String::from_utf8_lossy(read_name(record))
+    );
+    files::with_name(files::with_name(e, &position), name)
+}
+
+/// Copy a BAM input (e.g. unaligned reads) to output, reverse-complementing the i5 part of
+/// the BC tag of each record and reversing the corresponding part of the QT tag.
+/// The header and all other fields and tags are written unchanged.
+/// Records that cannot be rewritten are passed unchanged to `errors`.
+/// Rewritten records are counted in stats if given.
+/// Returns the number of records read.
+pub fn fix_bam(
+    input: &mut Input,
+    output: &mut Output,
+    errors: &mut ErrorHandler,
+    mut stats: Option<&mut Stats>,
+) -> io::Result<u64> {
+    let header = read_header(input).map_err(|e| input.error(e))?;
+    output.write_all(&header)?;
+    let mut record = Vec::new();
+    let mut records = 0;
+    while read_record(input, &mut record)
+        .map_err(|e| files::with_name(input.error(e), &format!("record {}", records + 1)))?
+    {
+        records += 1;
+        match fix_record(&mut record) {
+            Ok((i7, i5)) => {
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats.add_pair(&record[i7], &record[i5]);
+                }
+                output.write_all(&record)?;
+            }
+            Err(e) => {
+                let e = record_error(input.name(), records, &record, e);
+                errors.handle(e, records, &[&record[..]], std::slice::from_mut(output))?;
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    /// A BAM header with the SAM header `text` and no reference sequences.
+    fn header(text: &[u8]) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend((text.len() as u32).to_le_bytes());
+        header.extend(text);
+        header.extend(0u32.to_le_bytes());
+        header
+    }
+
+    /// An unmapped record with a sequence of 2 bases and the given (binary encoded) tags.
+    fn record(name: &[u8], tags: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend((-1i32).to_le_bytes()); // refID
+        data.extend((-1i32).to_le_bytes()); // pos
+        data.push(name.len() as u8 + 1); // l_read_name
+        data.push(0); // mapq
+        data.extend(4680u16.to_le_bytes()); // bin
+        data.extend(0u16.to_le_bytes()); // n_cigar_op
+        data.extend(4u16.to_le_bytes()); // flag: unmapped
+        data.extend(2u32.to_le_bytes()); // l_seq
+        data.extend((-1i32).to_le_bytes()); // next_refID
+        data.extend((-1i32).to_le_bytes()); // next_pos
+        data.extend(0i32.to_le_bytes()); // tlen
+        data.extend(name);
+        data.push(0);
+        data.push(0x12); // AC
+        data.extend(b"\x1e\x1e"); // qualities
+        data.extend(tags);
+        let mut record = (data.len() as u32).to_le_bytes().to_vec();
+        record.extend(data);
+        record
+    }
+
+    #[rstest]
+    #[case::bc_qt(b"BCZAAAA-ACGG\0QTZABCD EFGH\0", b"BCZAAAA-CCGT\0QTZABCD HGFE\0")]
+    #[case::qt_bc(b"QTZABCD EFGH\0BCZAAAA-ACGG\0", b"QTZABCD HGFE\0BCZAAAA-CCGT\0")]
+    #[case::b2_q2(b"BCZAAAA\0B2ZACGG\0Q2ZEFGH\0", b"BCZAAAA\0B2ZCCGT\0Q2ZHGFE\0")]
+    #[case::other_tags(
+        b"XAAxXcC\x01XsS\x02\0XiI\x03\0\0\0BCZAAAA-ACGG\0XBBc\x02\0\0\0\x01\x02RGZrg1\0",
+        b"XAAxXcC\x01XsS\x02\0XiI\x03\0\0\0BCZAAAA-CCGT\0XBBc\x02\0\0\0\x01\x02RGZrg1\0"
+    )]
+    fn rewrite_tags(#[case] tags: &[u8], #[case] expected: &[u8]) -> io::Result<()> {
+        let mut rewritten = record(b"r1", tags);
+        let (i7, i5) = fix_record(&mut rewritten)?;
+        assert_eq!(rewritten, record(b"r1", expected));
+        assert_eq!(&rewritten[i7], b"AAAA");
+        assert_eq!(&rewritten[i5], b"CCGT");
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::missing_bc(b"RGZrg1\0", "missing BC (or B2) tag")]
+    #[case::not_a_string(b"BCi\x01\0\0\0", "tag BC is not a string")]
+    #[case::no_separator(b"BCZAAAAACGG\0", "missing '-' between i7 and i5")]
+    #[case::truncated_tag(b"BCZAAAA-ACGG", "truncated tag")]
+    #[case::unknown_type(b"BCXAAAA-ACGG\0", "unknown type 'X'")]
+    fn invalid_tags(#[case] tags: &[u8], #[case] error: &str) {
+        let mut rewritten = record(b"r1", tags);
+        let msg = fix_record(&mut rewritten).unwrap_err().to_string();
+        assert!(msg.contains(error), "{msg}");
+        assert_eq!(rewritten, record(b"r1", tags));
+    }
+
+    #[test]
+    fn read_header_and_records() -> io::Result<()> {
+        let mut data = header(b"@HD\tVN:1.6\n");
+        let records = [record(b"r1", b"BCZA-C\0"), record(b"r2", b"")];
+        for record in &records {
+            data.extend(record);
+        }
+        let mut reader = &data[..];
+        assert_eq!(read_header(&mut reader)?, header(b"@HD\tVN:1.6\n"));
+        let mut record = Vec::new();
+        for expected in &records {
+            assert!(read_record(&mut reader, &mut record)?);
+            assert_eq!(&record, expected);
+        }
+        assert!(!read_record(&mut reader, &mut record)?);
+
+        let e = read_record(&mut &records[0][..30], &mut record).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+use flate2::write::DeflateEncoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Maximum number of uncompressed bytes in a BGZF block (as used by htslib),
+/// so that the compressed block is (almost always) smaller than 64 KiB.
+const BLOCK_DATA_BYTES: usize = 0xff00;
+
+/// Maximum size of a compressed BGZF block.
+const MAX_BLOCK_BYTES: usize = 1 << 16;
+
+/// Size of the header (including the BC extra field) and footer of a BGZF block.
+const HEADER_BYTES: usize = 18;
+const FOOTER_BYTES: usize = 8;
+
+/// Number of blocks per thread that are compressed (or waiting to be written) at a time.
+const BLOCKS_PER_THREAD: usize = 4;
+
+/// The empty block that marks the end of a BGZF file.
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0, 0x1b, 0, 0x03, 0, 0,
+    0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Compress `data` (at most [`BLOCK_DATA_BYTES`]) into a single BGZF block:
+/// a gzip member with a `BC` extra field holding the size of the block.
+fn compress_block(data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    let deflate = |level: u32| -> io::Result<Vec<u8>> {
+        let mut block = vec![0; HEADER_BYTES];
+        let mut encoder = DeflateEncoder::new(block, flate2::Compression::new(level));
+        encoder.write_all(data)?;
+        block = encoder.finish()?;
+        Ok(block)
+    };
+    let mut block = deflate(level)?;
+    if block.len() + FOOTER_BYTES > MAX_BLOCK_BYTES {
+        // incompressible data: stored blocks add only a few bytes
+        block = deflate(0)?;
+    }
+    let block_size = block.len() + FOOTER_BYTES;
+    let header: [u8; HEADER_BYTES] = [
+        0x1f,
+        0x8b,
+        0x08, // deflate
+        0x04, // FEXTRA
+        0,
+        0,
+        0,
+        0, // no modification time
+        0,
+        0xff, // unknown OS
+        6,
+        0, // length of the extra field
+        b'B',
+        b'C',
+        2,
+        0, // length of the BC subfield
+        ((block_size - 1) & 0xff) as u8,
+        ((block_size - 1) >> 8) as u8,
+    ];
+    block[..HEADER_BYTES].copy_from_slice(&header);
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(block)
+}
+
+/// Worker threads that compress the blocks of a [`Writer`] while it writes earlier blocks.
+/// Blocks are numbered in the order they are sent, and received in any order.
+struct Pool {
+    /// sends the number and data of each block to the workers (None once finished)
+    jobs: Option<mpsc::SyncSender<(u64, Vec<u8>)>>,
+    /// receives the number, uncompressed size and compressed block from the workers
+    results: mpsc::Receiver<(u64, usize, io::Result<Vec<u8>>)>,
+    workers: Vec<thread::JoinHandle<()>>,
+    /// number of the next block to send, and of the next block to write
+    sent: u64,
+    written: u64,
+    /// blocks that were compressed before the blocks that precede them
+    received: HashMap<u64, (usize, io::Result<Vec<u8>>)>,
+    /// maximum number of blocks sent but not written yet
+    max_pending: u64,
+}
+
+impl Pool {
+    fn new(threads: usize, level: u32) -> Self {
+        let (jobs, job_rx) = mpsc::sync_channel::<(u64, Vec<u8>)>(threads);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (result_tx, results) = mpsc::channel();
+        let workers = (0..threads)
+            .map(|_| {
+                let job_rx = Arc::clone(&job_rx);
+                let result_tx = result_tx.clone();
+                thread::spawn(move || loop {
+                    let job = job_rx.lock().expect("compression thread panicked").recv();
+                    let Ok((i, data)) = job else { break };
+                    let block = compress_block(&data, level);
+                    if result_tx.send((i, data.len(), block)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        Pool {
+            jobs: Some(jobs),
+            results,
+            workers,
+            sent: 0,
+            written: 0,
+            received: HashMap::new(),
+            max_pending: (threads * BLOCKS_PER_THREAD) as u64,
+        }
+    }
+
+    fn send(&mut self, data: Vec<u8>) -> io::Result<()> {
+        self.jobs
+            .as_ref()
+            .expect("blocks are only sent before finishing")
+            .send((self.sent, data))
+            .map_err(|_| io::Error::other("compression thread panicked"))?;
+        self.sent += 1;
+        Ok(())
+    }
+
+    /// The uncompressed size and compressed data of the next block to write,
+    /// or None if it is not compressed yet. Waits for it if `wait` is set,
+    /// or if too many blocks are pending. Returns None once all blocks sent were returned.
+    fn next(&mut self, wait: bool) -> io::Result<Option<(usize, Vec<u8>)>> {
+        loop {
+            if let Some((len, block)) = self.received.remove(&self.written) {
+                self.written += 1;
+                return block.map(|block| Some((len, block)));
+            }
+            let pending = self.sent - self.written;
+            if pending == 0 {
+                return Ok(None);
+            }
+            let result = if wait || pending >= self.max_pending {
+                self.results.recv().ok()
+            } else {
+                match self.results.try_recv() {
+                    Ok(result) => Some(result),
+                    Err(mpsc::TryRecvError::Empty) => return Ok(None),
+                    Err(mpsc::TryRecvError::Disconnected) => None,
+                }
+            };
+            let (i, len, block) =
+                result.ok_or_else(|| io::Error::other("compression thread panicked"))?;
+            self.received.insert(i, (len, block));
+        }
+    }
+
+    /// Stop the workers once they have compressed all blocks sent.
+    fn finish(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            worker.join().expect("compression thread panicked");
+        }
+    }
+}
+
+/// Writes BGZF (blocked gzip) compressed data, as used by htslib for random access.
+/// With more than one thread, blocks are compressed by a pool of worker threads
+/// while the previous blocks are written, and written in order.
+/// [`Writer::finish`] must be called to write the end-of-file marker and the `.gzi` index.
+pub struct Writer<W: Write> {
+    inner: W,
+    level: u32,
+    /// uncompressed data of the next block, compressed once it is full
+    buf: Vec<u8>,
+    /// None for a single thread, which compresses each block itself
+    pool: Option<Pool>,
+    compressed_offset: u64,
+    uncompressed_offset: u64,
+    /// path of the `.gzi` index and its entries (compressed and uncompressed offset of each block end)
+    index: Option<(PathBuf, Vec<(u64, u64)>)>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W, level: u32, threads: usize, index_path: Option<PathBuf>) -> Self {
+        Writer {
+            inner,
+            level,
+            buf: Vec::with_capacity(BLOCK_DATA_BYTES),
+            pool: (threads > 1).then(|| Pool::new(threads, level)),
+            compressed_offset: 0,
+            uncompressed_offset: 0,
+            index: index_path.map(|path| (path, Vec::new())),
+        }
+    }
+
+    /// Compress the buffered data as a block, or pass it to the workers and write the blocks
+    /// they have compressed so far.
+    fn end_block(&mut self) -> io::Result<()> {
+        let data = std::mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_DATA_BYTES));
+        match &mut self.pool {
+            None => {
+                let block = compress_block(&data, self.level)?;
+                self.write_block(data.len(), &block)
+            }
+            Some(pool) => {
+                pool.send(data)?;
+                self.write_compressed(false)
+            }
+        }
+    }
+
+    /// Write the blocks compressed by the workers in order, waiting for all of them if `wait`.
+    fn write_compressed(&mut self, wait: bool) -> io::Result<()> {
+        while let Some((len, block)) = match &mut self.pool {
+            Some(pool) => pool.next(wait)?,
+            None => None,
+        } {
+            self.write_block(len, &block)?;
+        }
+        Ok(())
+    }
+
+    /// Write a compressed block of `len` bytes of data.
+    fn write_block(&mut self, len: usize, block: &[u8]) -> io::Result<()> {
+        self.inner.write_all(block)?;
+        self.compressed_offset += block.len() as u64;
+        self.uncompressed_offset += len as u64;
+        if let Some((_, entries)) = &mut self.index {
+            entries.push((self.compressed_offset, self.uncompressed_offset));
+        }
+        Ok(())
+    }
+
+    /// Write the remaining data, the end-of-file marker and the index,
+    /// and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            self.end_block()?;
+        }
+        self.write_compressed(true)?;
+        if let Some(pool) = &mut self.pool {
+            pool.finish();
+        }
+        self.inner.write_all(&EOF_BLOCK)?;
+        if let Some((path, entries)) = &self.index {
+            let name = path.display().to_string();
+            File::create(path)
+                .and_then(|file| write_index(io::BufWriter::new(file), entries))
+                .map_err(|e| io::Error::new(e.kind(), format!("{name}: {e}")))?;
+        }
+        Ok(self.inner)
+    }
+}
+
+/// Write a `.gzi` index in the format of htslib: the number of entries followed by the
+/// compressed and uncompressed offset of the end of each block, all as little-endian u64.
+fn write_index<W: Write>(mut writer: W, entries: &[(u64, u64)]) -> io::Result<()> {
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for (compressed, uncompressed) in entries {
+        writer.write_all(&compressed.to_le_bytes())?;
+        writer.write_all(&uncompressed.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BLOCK_DATA_BYTES - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == BLOCK_DATA_BYTES {
+            self.end_block()?;
+        }
+        Ok(n)
+    }
+
+    /// Only complete blocks are written, as a flushed partial block would break up the block layout.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use rstest::rstest;
+    use std::io::Read;
+
+    #[rstest]
+    fn roundtrip(#[values(1, 2, 3)] threads: usize) -> io::Result<()> {
+        // compressible and incompressible data, spanning several blocks
+        let mut data = b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n".repeat(10000);
+        let mut x: u32 = 1;
+        data.extend((0..200_000).map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (x >> 16) as u8
+        }));
+        let mut writer = Writer::new(Vec::new(), 6, threads, None);
+        for chunk in data.chunks(10_000) {
+            writer.write_all(chunk)?;
+        }
+        let compressed = writer.finish()?;
+        assert!(compressed.ends_with(&EOF_BLOCK));
+
+        // each block has the correct size in its header
+        let mut offset = 0;
+        let mut n_blocks = 0;
+        while offset < compressed.len() {
+            let block_size =
+                u16::from_le_bytes([compressed[offset + 16], compressed[offset + 17]]) as usize + 1;
+            assert_eq!(compressed[offset..offset + 4], [0x1f, 0x8b, 8, 4]);
+            offset += block_size;
+            n_blocks += 1;
+        }
+        assert_eq!(offset, compressed.len());
+        assert_eq!(n_blocks, data.len().div_ceil(BLOCK_DATA_BYTES) + 1);
+
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(&compressed[..]).read_to_end(&mut decompressed)?;
+        assert!(decompressed == data);
+        Ok(())
+    }
+
+    #[test]
+    fn index_entries() -> io::Result<()> {
+        let mut index = Vec::new();
+        write_index(&mut index, &[(100, 65280), (180, 70000)])?;
+        assert_eq!(index.len(), 8 + 2 * 16);
+        assert_eq!(index[..8], 2u64.to_le_bytes());
+        assert_eq!(index[8..16], 100u64.to_le_bytes());
+        assert_eq!(index[16..24], 65280u64.to_le_bytes());
+        Ok(())
+    }
+}
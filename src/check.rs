@@ -0,0 +1,187 @@
+use crate::fastq::{Position, ReadOptions, Record};
+use crate::header::IndexLocation;
+use std::io::{self, BufRead};
+
+/// Number of records and problems found by [`check`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub records: u64,
+    pub problems: u64,
+}
+
+/// Parse all FASTQ records of input in the same way as when rewriting them, without writing
+/// any output, with the index field of the headers at `location`.
+/// Records are read as given by `options` (e.g. wrapped records).
+/// If `strict` is set, the whole record is validated, not just the header.
+/// Every problem found is passed to `report` along with the position of its record.
+/// Invalid headers are reported and checking continues with the next record, but checking
+/// stops at a truncated record or if the input cannot be read (e.g. a corrupt gzip stream).
+pub fn check<R: BufRead>(
+    input: &mut R,
+    options: ReadOptions,
+    strict: bool,
+    location: &IndexLocation,
+    mut report: impl FnMut(&Position, &io::Error),
+) -> Summary {
+    let mut summary = Summary::default();
+    let mut record = Record::new();
+    let mut position = Position::new();
+    loop {
+        let (lines, bytes) = match options.read_record(input, &mut record) {
+            Ok(Some(size)) => size,
+            Ok(None) => return summary,
+            Err(e) => {
+                report(&position, &e);
+                summary.problems += 1;
+                return summary;
+            }
+        };
+        summary.records += 1;
+        if let Err(e) = location.index_field(record.header()) {
+            report(&position, &e);
+            summary.problems += 1;
+        }
+        if strict {
+            if let Err(e) = record.validate() {
+                report(&position, &e);
+                summary.problems += 1;
+            }
+        }
+        position.advance_by(lines, bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastq::Wrapping;
+
+    fn problems(input: &[u8], strict: bool) -> (Summary, Vec<String>) {
+        problems_with(input, ReadOptions::default(), strict)
+    }
+
+    fn problems_with(input: &[u8], options: ReadOptions, strict: bool) -> (Summary, Vec<String>) {
+        let mut problems = Vec::new();
+        let summary = check(
+            &mut &input[..],
+            options,
+            strict,
+            &IndexLocation::default(),
+            |position, e| problems.push(format!("{position}: {e}")),
+        );
+        (summary, problems)
+    }
+
+    #[test]
+    fn check_valid() {
+        let (summary, problems) = problems(
+            b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n@r2 1:N:0:AAAA+AC\nA\n+\n!\n",
+            true,
+        );
+        assert_eq!(
+            summary,
+            Summary {
+                records: 2,
+                problems: 0
+            }
+        );
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn check_reports_every_problem() {
+        let (summary, problems) = problems(
+            b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n\
+r2 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n\
+@r3 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n\
+@r4 1:N:0:AAAAACGT\nACGT\n+\n!!!!\n\
+@r5 1:N:0:AAAA+ACGT\nACGT\n",
+            false,
+        );
+        assert_eq!(
+            summary,
+            Summary {
+                records: 4,
+                problems: 3
+            }
+        );
+        assert_eq!(
+            problems,
+            [
+                "record 2 (line 5, byte offset 32): invalid FASTQ header: does not start with '@'",
+                "record 4 (line 13, byte offset 95): invalid FASTQ header: missing '+' in index field",
+                "record 5 (line 17, byte offset 126): truncated FASTQ record (expected 4 lines)",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_strict() {
+        let input = b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!\nr2\nACGT\n-\n!!!!\n";
+        let (summary, _) = problems(input, false);
+        assert_eq!(
+            summary,
+            Summary {
+                records: 2,
+                problems: 1
+            }
+        );
+        let (summary, problems) = problems(input, true);
+        assert_eq!(
+            summary,
+            Summary {
+                records: 2,
+                problems: 3
+            }
+        );
+        assert_eq!(
+            problems,
+            [
+                "record 1 (line 1, byte offset 0): invalid FASTQ record: sequence length 4 does not match quality length 3",
+                "record 2 (line 5, byte offset 31): invalid FASTQ header: does not start with '@'",
+                "record 2 (line 5, byte offset 31): invalid FASTQ record: separator line does not start with '+'",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_strict_repeated_title() {
+        let (summary, problems) = problems(
+            b"@r1 1:N:0:AAAA+ACGT\nACGT\n+r1 1:N:0:AAAA+ACGT\n!!!!\n\
+@r2 1:N:0:AAAA+ACGT\nACGT\n+r2 1:N:0:AAAA+ACGA\n!!!!\n",
+            true,
+        );
+        assert_eq!(
+            summary,
+            Summary {
+                records: 2,
+                problems: 1
+            }
+        );
+        assert_eq!(
+            problems,
+            ["record 2 (line 5, byte offset 50): invalid FASTQ record: separator line title 'r2 1:N:0:AAAA+ACGA' does not match header title 'r2 1:N:0:AAAA+ACGT'"]
+        );
+    }
+
+    #[test]
+    fn check_wrapped() {
+        let input = b"@r1 1:N:0:AAAA+ACGT\nAC\nGT\n+\n!!\n!!\n@r2 1:N:0:AAAA+ACGT\nACGT\n+\n!! !\n";
+        let options = ReadOptions {
+            wrapping: Wrapping::Unwrap,
+            ..ReadOptions::default()
+        };
+        let (summary, problems) = problems_with(input, options, true);
+        assert_eq!(
+            summary,
+            Summary {
+                records: 2,
+                problems: 1
+            }
+        );
+        assert_eq!(
+            problems,
+            ["record 2 (line 7, byte offset 34): invalid FASTQ record: quality character ' ' at position 3 is outside the valid range '!' to '~'"]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use crate::fastq::{self, Position, Record, N_LINES_PER_RECORD};
+use crate::files::IO_BUFFER_BYTES;
+use std::io::{self, Read, Write};
+
+/// Initial size of the buffer of a [`ChunkReader`], it grows if a single record does not fit.
+const CHUNK_BYTES: usize = 4 * IO_BUFFER_BYTES;
+
+/// Reads FASTQ records into a large buffer, where they can be rewritten in place,
+/// and writes them from there without copying them record by record.
+/// Records that are not [`cut`](ChunkReader::cut) out are written to the output in contiguous
+/// spans whenever the buffer is refilled, and only the incomplete record at the end of the
+/// buffer is moved to the start of the buffer.
+pub struct ChunkReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// end of the valid data in buf
+    end: usize,
+    /// true once the reader has no more data
+    at_eof: bool,
+    /// start of the records that have not been written yet
+    unwritten: usize,
+    /// start of the current record
+    start: usize,
+    line_ends: [usize; N_LINES_PER_RECORD],
+    /// position of the current record, or of the next record before the first one is read
+    position: Position,
+    has_record: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_capacity(reader, CHUNK_BYTES)
+    }
+
+    /// A reader for the rest of an input, which starts with the record at `position`.
+    pub fn resume(reader: R, position: Position) -> Self {
+        let mut records = Self::new(reader);
+        records.position = position;
+        records
+    }
+
+    fn with_capacity(reader: R, capacity: usize) -> Self {
+        ChunkReader {
+            reader,
+            buf: vec![0; capacity],
+            end: 0,
+            at_eof: false,
+            unwritten: 0,
+            start: 0,
+            line_ends: [0; N_LINES_PER_RECORD],
+            position: Position::new(),
+            has_record: false,
+        }
+    }
+
+    /// End of the current record in buf.
+    fn record_end(&self) -> usize {
+        if self.has_record {
+            self.start + self.line_ends[N_LINES_PER_RECORD - 1]
+        } else {
+            self.start
+        }
+    }
+
+    /// Position of the current record, or of the record that failed to be read.
+    pub fn position(&self) -> &Position {
+        &self.position
+    }
+
+    /// Move to the next record. The records before it that were not cut out are written
+    /// to output if the buffer needs to be refilled. Returns false at the end of the input,
+    /// and an error if the last record is truncated.
+    pub fn next_record<W: Write>(&mut self, output: &mut W) -> io::Result<bool> {
+        if self.has_record {
+            let end = self.record_end();
+            let record = Record::from_line_ends(&self.buf[self.start..end], self.line_ends);
+            self.position.advance(&record);
+            self.start = end;
+            self.has_record = false;
+        }
+        loop {
+            let data = &self.buf[self.start..self.end];
+            if let Some(line_ends) = fastq::find_record(data, self.at_eof) {
+                self.line_ends = line_ends;
+                self.has_record = true;
+                return Ok(true);
+            }
+            if self.at_eof {
+                if data.is_empty() {
+                    return Ok(false);
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated FASTQ record (expected 4 lines)",
+                ));
+            }
+            self.refill(output)?;
+        }
+    }
+
+    /// Write the unwritten records to output, move the incomplete record at the end
+    /// of the buffer to its start, and read more data after it.
+    fn refill<W: Write>(&mut self, output: &mut W) -> io::Result<()> {
+        output.write_all(&self.buf[self.unwritten..self.start])?;
+        self.buf.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        self.unwritten = 0;
+        if self.end == self.buf.len() {
+            self.buf.resize(2 * self.buf.len(), 0);
+        }
+        let n = loop {
+            match self.reader.read(&mut self.buf[self.end..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        self.end += n;
+        self.at_eof = n == 0;
+        Ok(())
+    }
+
+    /// The current record.
+    pub fn record(&self) -> Record<&[u8]> {
+        Record::from_line_ends(&self.buf[self.start..self.record_end()], self.line_ends)
+    }
+
+    /// The header of the current record.
+    pub fn header(&self) -> &[u8] {
+        &self.buf[self.start..self.start + self.line_ends[0]]
+    }
+
+    /// The current record, to rewrite it in place.
+    pub fn record_mut(&mut self) -> Record<&mut [u8]> {
+        let end = self.record_end();
+        Record::from_line_ends(&mut self.buf[self.start..end], self.line_ends)
+    }
+
+    /// Write the records before the current one to output, and leave the current record out.
+    pub fn cut<W: Write>(&mut self, output: &mut W) -> io::Result<()> {
+        output.write_all(&self.buf[self.unwritten..self.start])?;
+        self.unwritten = self.record_end();
+        Ok(())
+    }
+
+    /// Write all records up to and including the current one to output.
+    pub fn flush<W: Write>(&mut self, output: &mut W) -> io::Result<()> {
+        let end = self.record_end();
+        output.write_all(&self.buf[self.unwritten..end])?;
+        self.unwritten = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const INPUT: &[u8] = b"@r1 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n@r2 1:N:0:AAAA+TTTCAGGAAAAA\nACGTACGTACGTACGTACGTACGT\n+\n!!!!!!!!!!!!!!!!!!!!!!!!\n@r3 1:N:0:AAAA+C\nA\n+\n!";
+
+    #[rstest]
+    fn records_and_spans(#[values(1, 7, 32, 1024)] capacity: usize) -> io::Result<()> {
+        let mut reader = ChunkReader::with_capacity(INPUT, capacity);
+        let mut output = Vec::new();
+        let mut headers = Vec::new();
+        while reader.next_record(&mut output)? {
+            let mut record = reader.record_mut();
+            headers.push(String::from_utf8_lossy(record.header()).into_owned());
+            record.header_mut()[0] = b'>';
+            if reader.position().record == 2 {
+                reader.cut(&mut output)?;
+            }
+        }
+        reader.flush(&mut output)?;
+        assert_eq!(
+            headers,
+            [
+                "@r1 1:N:0:AAAA+ACGG\n",
+                "@r2 1:N:0:AAAA+TTTCAGGAAAAA\n",
+                "@r3 1:N:0:AAAA+C\n"
+            ]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            ">r1 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n>r3 1:N:0:AAAA+C\nA\n+\n!"
+        );
+        // at the end, the position is that of a next record
+        assert_eq!(reader.position().line, 13);
+        Ok(())
+    }
+
+    #[test]
+    fn truncated() -> io::Result<()> {
+        let mut reader = ChunkReader::with_capacity(&b"@r1\nA\n+\n!\n@r2\nA\n"[..], 4);
+        let mut output = Vec::new();
+        assert!(reader.next_record(&mut output)?);
+        let e = reader.next_record(&mut output).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(reader.position().record, 2);
+        assert_eq!(reader.position().offset, 10);
+        Ok(())
+    }
+}
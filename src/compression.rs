@@ -0,0 +1,347 @@
+use crate::bgzf;
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// First bytes of a gzip stream (RFC 1952).
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// First bytes of a zstd frame (RFC 8878).
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// First bytes of a bzip2 stream, followed by the block size digit.
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+
+/// First bytes of an xz stream.
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+
+/// Number of bytes needed to identify all supported compression formats.
+const MAGIC_BYTES: usize = 6;
+
+/// Largest window (as a power of 2) accepted when decompressing zstd input,
+/// so that files compressed with a long window (e.g. `zstd --long=31`) can be read.
+const ZSTD_MAX_WINDOW_LOG: u32 = 31;
+
+/// Highest compression level of each format.
+const MAX_GZIP_LEVEL: u32 = 9;
+pub const MAX_ZSTD_LEVEL: u32 = 22;
+
+/// Compression format of the output stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// Uncompressed FASTQ
+    None,
+    /// gzip compressed FASTQ
+    Gzip,
+    /// BGZF (blocked gzip) compressed FASTQ, which can be indexed for random access
+    Bgzf,
+    /// Zstandard compressed FASTQ
+    Zstd,
+}
+
+/// Settings for compressing an output.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// compression level (0-9 for gzip and BGZF, 0-22 for zstd)
+    pub level: u32,
+    /// number of threads compressing BGZF blocks or zstd output
+    pub threads: usize,
+    /// also write a `.gzi` index for BGZF output
+    pub index: bool,
+    /// enable zstd long distance matching with a window of this size (as a power of 2)
+    pub zstd_window_log: Option<u32>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            level: 6,
+            threads: 1,
+            index: false,
+            zstd_window_log: None,
+        }
+    }
+}
+
+impl Compression {
+    /// Infer the compression format from the extension of a file name.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("bgz") => Compression::Bgzf,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// File extension (including the leading '.') for this compression format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip | Compression::Bgzf => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// Compressed input formats that are decompressed transparently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InputFormat {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl InputFormat {
+    /// The compressed format identified by the first bytes of a stream, if any.
+    /// BGZF is identified as gzip, which it is compatible with.
+    fn detect(prefix: &[u8]) -> Option<Self> {
+        if prefix.starts_with(&GZIP_MAGIC) {
+            Some(InputFormat::Gzip)
+        } else if prefix.starts_with(&ZSTD_MAGIC) {
+            Some(InputFormat::Zstd)
+        } else if prefix.starts_with(&XZ_MAGIC) {
+            Some(InputFormat::Xz)
+        } else if prefix.starts_with(&BZIP2_MAGIC)
+            && prefix.get(3).is_some_and(|d| (b'1'..=b'9').contains(d))
+        {
+            Some(InputFormat::Bzip2)
+        } else {
+            None
+        }
+    }
+}
+
+/// Read up to `buf.len()` bytes, stopping early only at EOF.
+/// Returns the number of bytes read.
+fn read_prefix<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Read the first (up to) `n` bytes of `reader`, and return them together with
+/// a reader that yields all of the data, including these bytes.
+pub fn peek<R: Read>(mut reader: R, n: usize) -> io::Result<(Vec<u8>, impl Read)> {
+    let mut prefix = vec![0; n];
+    let n = read_prefix(&mut reader, &mut prefix)?;
+    prefix.truncate(n);
+    Ok((prefix.clone(), io::Cursor::new(prefix).chain(reader)))
+}
+
+/// Check if the first bytes read from `reader` identify a compressed format.
+pub fn is_compressed<R: Read>(reader: &mut R) -> io::Result<bool> {
+    let mut magic = [0u8; MAGIC_BYTES];
+    let n = read_prefix(reader, &mut magic)?;
+    Ok(InputFormat::detect(&magic[..n]).is_some())
+}
+
+/// Wrap `reader` in a decompressor if its first bytes identify a compressed format
+/// (gzip, bzip2, xz or zstd), otherwise return it unchanged.
+pub fn decompressed<R: Read + Send + 'static>(reader: R) -> io::Result<Box<dyn Read + Send>> {
+    let (magic, reader) = peek(reader, MAGIC_BYTES)?;
+    Ok(match InputFormat::detect(&magic) {
+        Some(InputFormat::Gzip) => Box::new(DecodingReader {
+            format: "gzip",
+            decoder: MultiGzDecoder::new(reader),
+        }),
+        Some(InputFormat::Bzip2) => Box::new(DecodingReader {
+            format: "bzip2",
+            decoder: bzip2::read::MultiBzDecoder::new(reader),
+        }),
+        Some(InputFormat::Xz) => Box::new(DecodingReader {
+            format: "xz",
+            decoder: liblzma::read::XzDecoder::new_multi_decoder(reader),
+        }),
+        Some(InputFormat::Zstd) => {
+            let mut decoder = zstd::Decoder::new(reader)?;
+            decoder.window_log_max(ZSTD_MAX_WINDOW_LOG)?;
+            Box::new(DecodingReader {
+                format: "zstd",
+                decoder,
+            })
+        }
+        None => Box::new(reader),
+    })
+}
+
+/// Decoder that labels decoding errors, so that a corrupt or truncated
+/// compressed stream is not mistaken for a truncated FASTQ record.
+struct DecodingReader<R: Read> {
+    format: &'static str,
+    decoder: R,
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf).map_err(|e| {
+            let msg = if e.kind() == io::ErrorKind::UnexpectedEof {
+                format!("truncated {} input: {e}", self.format)
+            } else {
+                format!("invalid {} input: {e}", self.format)
+            };
+            io::Error::new(e.kind(), msg)
+        })
+    }
+}
+
+/// Output stream that optionally compresses everything written to it.
+/// [`Writer::finish`] must be called to complete the compressed stream.
+pub enum Writer<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Bgzf(bgzf::Writer<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Writer<W> {
+    /// `index_path` is the path of the `.gzi` index for BGZF compression, if any.
+    /// Returns an error if the options are not supported by the compression format.
+    pub fn new(
+        inner: W,
+        compression: Compression,
+        options: &Options,
+        index_path: Option<PathBuf>,
+    ) -> io::Result<Self> {
+        let (format, max_level) = match compression {
+            Compression::None => ("uncompressed", u32::MAX),
+            Compression::Gzip | Compression::Bgzf => ("gzip and BGZF", MAX_GZIP_LEVEL),
+            Compression::Zstd => ("zstd", MAX_ZSTD_LEVEL),
+        };
+        if options.level > max_level {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "compression level {} is not supported for {format} output (0-{max_level})",
+                    options.level
+                ),
+            ));
+        }
+        Ok(match compression {
+            Compression::None => Writer::Plain(inner),
+            Compression::Gzip => Writer::Gzip(GzEncoder::new(
+                inner,
+                flate2::Compression::new(options.level),
+            )),
+            Compression::Bgzf => Writer::Bgzf(bgzf::Writer::new(
+                inner,
+                options.level,
+                options.threads,
+                index_path,
+            )),
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(inner, options.level as i32)?;
+                if options.threads > 1 {
+                    encoder.multithread(options.threads as u32)?;
+                }
+                if let Some(window_log) = options.zstd_window_log {
+                    encoder.long_distance_matching(true)?;
+                    encoder.window_log(window_log)?;
+                }
+                Writer::Zstd(encoder)
+            }
+        })
+    }
+
+    /// Write any trailing compressed data and flush the underlying writer.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Writer::Plain(mut w) => w.flush(),
+            Writer::Gzip(w) => w.finish()?.flush(),
+            Writer::Bgzf(w) => w.finish()?.flush(),
+            Writer::Zstd(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Plain(w) => w.write(buf),
+            Writer::Gzip(w) => w.write(buf),
+            Writer::Bgzf(w) => w.write(buf),
+            Writer::Zstd(w) => w.write(buf),
+        }
+    }
+
+    #[inline(always)]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Writer::Plain(w) => w.write_all(buf),
+            Writer::Gzip(w) => w.write_all(buf),
+            Writer::Bgzf(w) => w.write_all(buf),
+            Writer::Zstd(w) => w.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Plain(w) => w.flush(),
+            Writer::Gzip(w) => w.flush(),
+            Writer::Bgzf(w) => w.flush(),
+            Writer::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const FASTQ: &[u8] = b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n";
+
+    fn compress(format: Option<InputFormat>, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        match format {
+            None => compressed.extend_from_slice(data),
+            Some(InputFormat::Gzip) => {
+                let mut w = GzEncoder::new(&mut compressed, flate2::Compression::default());
+                w.write_all(data).unwrap();
+                w.finish().unwrap();
+            }
+            Some(InputFormat::Bzip2) => {
+                let mut w = bzip2::write::BzEncoder::new(&mut compressed, Default::default());
+                w.write_all(data).unwrap();
+                w.finish().unwrap();
+            }
+            Some(InputFormat::Xz) => {
+                let mut w = liblzma::write::XzEncoder::new(&mut compressed, 6);
+                w.write_all(data).unwrap();
+                w.finish().unwrap();
+            }
+            Some(InputFormat::Zstd) => compressed = zstd::encode_all(data, 3).unwrap(),
+        }
+        compressed
+    }
+
+    #[rstest]
+    fn decompress_formats(
+        #[values(
+            None,
+            Some(InputFormat::Gzip),
+            Some(InputFormat::Bzip2),
+            Some(InputFormat::Xz),
+            Some(InputFormat::Zstd)
+        )]
+        format: Option<InputFormat>,
+    ) -> io::Result<()> {
+        let compressed = compress(format, FASTQ);
+        assert_eq!(InputFormat::detect(&compressed[..MAGIC_BYTES]), format);
+        let mut data = Vec::new();
+        decompressed(io::Cursor::new(compressed))?.read_to_end(&mut data)?;
+        assert_eq!(data, FASTQ);
+        Ok(())
+    }
+}
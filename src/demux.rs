@@ -0,0 +1,315 @@
+use crate::dna::reverse_complement_in_place;
+use crate::fastq::{self, Record};
+use crate::files::{self, Input, Output};
+use crate::header::IndexLocation;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Name of the output for records that do not match any sample.
+pub const UNDETERMINED: &str = "Undetermined";
+
+/// Accepted (case-insensitive) column names in a sample sheet.
+const SAMPLE_COLUMNS: [&str; 4] = ["sample", "sample_id", "sample_name", "samplename"];
+const I7_COLUMNS: [&str; 3] = ["i7", "index", "index1"];
+const I5_COLUMNS: [&str; 2] = ["i5", "index2"];
+
+/// A sample and its expected (upper case) i7 and i5 index sequences.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub name: String,
+    pub i7: Vec<u8>,
+    pub i5: Vec<u8>,
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parse a sample sheet: comma or tab separated values with a header line that names
+/// the sample, i7 and i5 columns. Empty lines and lines starting with '#' are ignored.
+pub fn parse_sample_sheet(text: &str) -> io::Result<Vec<Sample>> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim_end_matches('\r')))
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+    let Some((_, header)) = lines.next() else {
+        return Err(invalid_data("sample sheet is empty".to_string()));
+    };
+    let delimiter = if header.contains('\t') { '\t' } else { ',' };
+    let columns: Vec<String> = header
+        .split(delimiter)
+        .map(|c| c.trim().to_ascii_lowercase())
+        .collect();
+    let column = |names: &[&str]| {
+        columns
+            .iter()
+            .position(|c| names.contains(&c.as_str()))
+            .ok_or_else(|| {
+                invalid_data(format!(
+                    "sample sheet header has no column named {}",
+                    names.join(" or ")
+                ))
+            })
+    };
+    let (name_col, i7_col, i5_col) = (
+        column(&SAMPLE_COLUMNS)?,
+        column(&I7_COLUMNS)?,
+        column(&I5_COLUMNS)?,
+    );
+
+    let mut samples = Vec::new();
+    for (line_number, line) in lines {
+        let fields: Vec<&str> = line.split(delimiter).map(str::trim).collect();
+        let field = |col: usize| {
+            fields.get(col).copied().ok_or_else(|| {
+                invalid_data(format!(
+                    "sample sheet line {line_number}: expected at least {} fields",
+                    col + 1
+                ))
+            })
+        };
+        let name = field(name_col)?;
+        if name.is_empty() || name == UNDETERMINED || name.contains(['/', '\\']) || name == ".." {
+            return Err(invalid_data(format!(
+                "sample sheet line {line_number}: invalid sample name '{name}'"
+            )));
+        }
+        let index = |col: usize| -> io::Result<Vec<u8>> {
+            let seq = field(col)?.to_ascii_uppercase().into_bytes();
+            if seq.is_empty() || !seq.iter().all(|b| b"ACGTN".contains(b)) {
+                return Err(invalid_data(format!(
+                    "sample sheet line {line_number}: invalid index sequence '{}'",
+                    String::from_utf8_lossy(&seq)
+                )));
+            }
+            Ok(seq)
+        };
+        samples.push(Sample {
+            name: name.to_string(),
+            i7: index(i7_col)?,
+            i5: index(i5_col)?,
+        });
+    }
+    Ok(samples)
+}
+
+/// Read and parse a sample sheet file, see [`parse_sample_sheet`].
+pub fn read_sample_sheet(path: &Path) -> io::Result<Vec<Sample>> {
+    let name = path.display().to_string();
+    std::fs::read_to_string(path)
+        .and_then(|text| parse_sample_sheet(&text))
+        .map_err(|e| files::with_name(e, &name))
+}
+
+/// Number of positions at which the (equal length) sequences differ, ignoring case.
+/// Returns None if the lengths differ or there are more than `max` mismatches.
+fn mismatches(a: &[u8], b: &[u8], max: usize) -> Option<usize> {
+    if a.len() != b.len() {
+        return None;
+    }
+    let mut n = 0;
+    for (x, y) in a.iter().zip(b) {
+        if !x.eq_ignore_ascii_case(y) {
+            n += 1;
+            if n > max {
+                return None;
+            }
+        }
+    }
+    Some(n)
+}
+
+/// Assigns i7/i5 index pairs to samples, allowing a number of mismatches in each index.
+pub struct Demultiplexer {
+    samples: Vec<Sample>,
+    max_i7_mismatches: usize,
+    max_i5_mismatches: usize,
+    /// i7 -> i5 -> sample for exact (upper case) matches
+    exact: HashMap<Vec<u8>, HashMap<Vec<u8>, usize>>,
+}
+
+impl Demultiplexer {
+    /// Returns an error if an index pair could match more than one sample
+    /// within the given mismatch tolerances.
+    pub fn new(
+        samples: Vec<Sample>,
+        max_i7_mismatches: usize,
+        max_i5_mismatches: usize,
+    ) -> io::Result<Self> {
+        for (i, a) in samples.iter().enumerate() {
+            for b in samples[i + 1..].iter().filter(|b| b.name != a.name) {
+                // a read can be within tolerance of both samples if the two sample
+                // indexes are within twice the tolerance of each other
+                if mismatches(&a.i7, &b.i7, 2 * max_i7_mismatches).is_some()
+                    && mismatches(&a.i5, &b.i5, 2 * max_i5_mismatches).is_some()
+                {
+                    return Err(invalid_data(format!(
+                        "samples '{}' and '{}' are ambiguous: their indexes are too similar for {} i7 and {} i5 mismatches",
+                        a.name, b.name, max_i7_mismatches, max_i5_mismatches
+                    )));
+                }
+            }
+        }
+        let mut exact: HashMap<Vec<u8>, HashMap<Vec<u8>, usize>> = HashMap::new();
+        for (i, s) in samples.iter().enumerate() {
+            exact
+                .entry(s.i7.clone())
+                .or_default()
+                .entry(s.i5.clone())
+                .or_insert(i);
+        }
+        Ok(Demultiplexer {
+            samples,
+            max_i7_mismatches,
+            max_i5_mismatches,
+            exact,
+        })
+    }
+
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// The index of the sample matching the given index pair, if any.
+    pub fn assign(&self, i7: &[u8], i5: &[u8]) -> Option<usize> {
+        if let Some(&i) = self.exact.get(i7).and_then(|by_i5| by_i5.get(i5)) {
+            return Some(i);
+        }
+        // samples are not ambiguous, so at most one can match
+        self.samples.iter().position(|s| {
+            mismatches(&s.i7, i7, self.max_i7_mismatches).is_some()
+                && mismatches(&s.i5, i5, self.max_i5_mismatches).is_some()
+        })
+    }
+}
+
+/// Write each FASTQ record from input to the output of the sample that matches its index pair,
+/// or to undetermined if there is no match. `sample_outputs` maps each sample to an output.
+/// The index pair is found at `location` in the header. If `rc_i5` is set,
+/// the i5 in the header is reverse-complemented before matching.
+pub fn demux(
+    input: &mut Input,
+    demultiplexer: &Demultiplexer,
+    location: &IndexLocation,
+    rc_i5: bool,
+    sample_outputs: &[usize],
+    outputs: &mut [Output],
+    undetermined: &mut Output,
+) -> io::Result<()> {
+    let mut record = Record::new();
+    while fastq::read_record(input, &mut record).map_err(|e| input.error(e))? {
+        let (i7, i5) = location
+            .index_field(record.header())
+            .map_err(|e| input.error(e))?;
+        if rc_i5 {
+            reverse_complement_in_place(&mut record.header_mut()[i5.clone()]);
+        }
+        let header = record.header();
+        let output = match demultiplexer.assign(&header[i7], &header[i5]) {
+            Some(sample) => &mut outputs[sample_outputs[sample]],
+            None => &mut *undetermined,
+        };
+        output.write_all(record.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn sample(name: &str, i7: &str, i5: &str) -> Sample {
+        Sample {
+            name: name.to_string(),
+            i7: i7.as_bytes().to_vec(),
+            i5: i5.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn parse_sample_sheet_valid() -> io::Result<()> {
+        let csv = "# comment\nSample_ID,index,index2,Project\r\nS1,ACGT,ttga,P\n\nS2,CCCC,GGGG,P\n";
+        assert_eq!(
+            parse_sample_sheet(csv)?,
+            vec![sample("S1", "ACGT", "TTGA"), sample("S2", "CCCC", "GGGG")]
+        );
+        let tsv = "i5\ti7\tsample\nTTGA\tACGT\tS1\n";
+        assert_eq!(parse_sample_sheet(tsv)?, vec![sample("S1", "ACGT", "TTGA")]);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::empty("", "empty")]
+    #[case::missing_column("sample,i7\nS1,ACGT\n", "i5 or index2")]
+    #[case::missing_field("sample,i7,i5\nS1,ACGT\n", "line 2")]
+    #[case::invalid_base("sample,i7,i5\nS1,ACGT,ACXT\n", "'ACXT'")]
+    #[case::empty_index("sample,i7,i5\nS1,ACGT,\n", "''")]
+    #[case::path_name("sample,i7,i5\n../S1,ACGT,ACGT\n", "'../S1'")]
+    #[case::undetermined_name("sample,i7,i5\nUndetermined,ACGT,ACGT\n", "'Undetermined'")]
+    fn parse_sample_sheet_invalid(#[case] text: &str, #[case] msg_substr: &str) {
+        let msg = parse_sample_sheet(text).unwrap_err().to_string();
+        assert!(msg.contains(msg_substr), "{msg}");
+    }
+
+    #[rstest]
+    #[case::exact(b"ACGT", b"ACGT", 0, Some(0))]
+    #[case::case_insensitive(b"acgt", b"ACGT", 0, Some(0))]
+    #[case::one(b"ACGA", b"ACGT", 1, Some(1))]
+    #[case::too_many(b"AAGA", b"ACGT", 1, None)]
+    #[case::n_is_mismatch(b"ACGN", b"ACGT", 0, None)]
+    #[case::length(b"ACG", b"ACGT", 1, None)]
+    fn mismatches_counts(
+        #[case] a: &[u8],
+        #[case] b: &[u8],
+        #[case] max: usize,
+        #[case] expected: Option<usize>,
+    ) {
+        assert_eq!(mismatches(a, b, max), expected);
+    }
+
+    #[test]
+    fn demultiplexer_assign() -> io::Result<()> {
+        let d = Demultiplexer::new(
+            vec![
+                sample("S1", "AAAAAA", "CCCCCC"),
+                sample("S2", "GGGGGG", "TTTTTT"),
+                sample("S3", "AAAAAA", "GGGGGG"),
+            ],
+            1,
+            1,
+        )?;
+        assert_eq!(d.assign(b"AAAAAA", b"CCCCCC"), Some(0));
+        assert_eq!(d.assign(b"gggggg", b"tttttt"), Some(1));
+        assert_eq!(d.assign(b"AAAANA", b"GGGGGC"), Some(2));
+        assert_eq!(d.assign(b"AAAANN", b"GGGGGG"), None);
+        assert_eq!(d.assign(b"AAAAAA", b"GGGG"), None);
+        assert_eq!(d.assign(b"TTTTTT", b"TTTTTT"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn demultiplexer_ambiguous() {
+        let samples = || {
+            vec![
+                sample("S1", "AAAAAA", "CCCCCC"),
+                sample("S2", "AAAATT", "CCCCCC"),
+            ]
+        };
+        // i7 differ by 2: fine with 0 mismatches, ambiguous with 1
+        assert!(Demultiplexer::new(samples(), 0, 1).is_ok());
+        let msg = Demultiplexer::new(samples(), 1, 0)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(msg.contains("'S1' and 'S2' are ambiguous"), "{msg}");
+        // the same sample can be listed more than once
+        let lanes = vec![
+            sample("S1", "AAAAAA", "CCCCCC"),
+            sample("S1", "AAAAAA", "CCCCCC"),
+        ];
+        assert!(Demultiplexer::new(lanes, 1, 1).is_ok());
+    }
+}
@@ -0,0 +1,33 @@
+/// Return the complement of a DNA base (A,C,G,T,N), preserving case.
+#[inline(always)]
+pub const fn complement_base(b: u8) -> u8 {
+    // Handles A,C,G,T,N (upper/lower). Leaves other bytes unchanged.
+    match b {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'N' => b'N',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        b'n' => b'n',
+        _ => b,
+    }
+}
+
+/// Reverse-complement a DNA sequence in-place.
+#[inline(always)]
+pub fn reverse_complement_in_place(buf: &mut [u8]) {
+    let mut i = 0;
+    let mut j = buf.len();
+    while i < j {
+        j -= 1;
+        let a = complement_base(buf[i]);
+        let b = complement_base(buf[j]);
+        buf[i] = b;
+        buf[j] = a;
+        i += 1;
+    }
+}
@@ -0,0 +1,606 @@
+use memchr::{memchr, memchr2};
+use std::io::{self, BufRead};
+use std::ops::Range;
+
+/// Number of lines in a FASTQ record (the first line is the header).
+pub const N_LINES_PER_RECORD: usize = 4;
+
+/// How the sequence and quality of FASTQ records are laid out in lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Wrapping {
+    /// A single sequence and quality line, so 4 lines per record
+    #[default]
+    None,
+    /// Sequence and quality may be wrapped over several lines, which are kept as they are
+    Keep,
+    /// Sequence and quality may be wrapped over several lines, which are joined into one line each
+    Unwrap,
+}
+
+/// Line endings of the records written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LineEndings {
+    /// Keep the line endings of the input (LF or CRLF)
+    #[default]
+    Keep,
+    /// Convert CRLF line endings to LF
+    Lf,
+}
+
+/// How to read FASTQ records: the layout of their lines, and their line endings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadOptions {
+    pub wrapping: Wrapping,
+    pub line_endings: LineEndings,
+}
+
+impl ReadOptions {
+    /// Read the next FASTQ record into record (see [`read_record`] and [`read_wrapped_record`]),
+    /// converting its line endings to LF if requested.
+    /// Returns the number of lines and bytes read from the input, or None on EOF.
+    pub fn read_record<R: BufRead>(
+        self,
+        reader: &mut R,
+        record: &mut Record,
+    ) -> io::Result<Option<(u64, u64)>> {
+        let size = match self.wrapping {
+            Wrapping::None => read_record(reader, record)?
+                .then(|| (N_LINES_PER_RECORD as u64, record.as_bytes().len() as u64)),
+            Wrapping::Keep => read_wrapped_record(reader, record, false)?,
+            Wrapping::Unwrap => read_wrapped_record(reader, record, true)?,
+        };
+        if size.is_some() && self.line_endings == LineEndings::Lf {
+            record.normalize_line_endings();
+        }
+        Ok(size)
+    }
+}
+
+/// Range of valid (Phred+33) quality characters.
+const MIN_QUALITY: u8 = b'!';
+const MAX_QUALITY: u8 = b'~';
+
+/// A FASTQ record stored as its header, sequence, separator and quality lines,
+/// each including the trailing newline. By default the record owns its buffer,
+/// but it can also be a view of a record in a larger buffer (see [`find_record`]).
+pub struct Record<B = Vec<u8>> {
+    buf: B,
+    line_ends: [usize; N_LINES_PER_RECORD],
+}
+
+impl Record {
+    pub fn new() -> Self {
+        Record {
+            buf: Vec::with_capacity(1024),
+            line_ends: [0; N_LINES_PER_RECORD],
+        }
+    }
+
+    /// Replace all CRLF line endings of the record (including those within wrapped lines) by LF.
+    pub fn normalize_line_endings(&mut self) {
+        let mut start = 0;
+        let mut end = 0;
+        let mut line = 0;
+        while let Some(i) = memchr(b'\n', &self.buf[start..]) {
+            let newline = start + i;
+            let content_end = if self.buf[start..newline].ends_with(b"\r") {
+                newline - 1
+            } else {
+                newline
+            };
+            self.buf.copy_within(start..content_end, end);
+            end += content_end - start;
+            self.buf[end] = b'\n';
+            end += 1;
+            start = newline + 1;
+            while line < N_LINES_PER_RECORD && self.line_ends[line] <= start {
+                self.line_ends[line] = end;
+                line += 1;
+            }
+        }
+        let len = self.buf.len();
+        self.buf.copy_within(start..len, end);
+        self.buf.truncate(end + len - start);
+        for line_end in &mut self.line_ends[line..] {
+            *line_end = self.buf.len();
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Record<B> {
+    /// A record stored in `buf`, with the line ends found by [`find_record`].
+    pub fn from_line_ends(buf: B, line_ends: [usize; N_LINES_PER_RECORD]) -> Self {
+        Record { buf, line_ends }
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.buf.as_ref()[..self.line_ends[0]]
+    }
+
+    /// Range of line `i` of the record, excluding the trailing newline ('\n' or "\r\n").
+    fn line_content(&self, i: usize) -> Range<usize> {
+        let start = if i == 0 { 0 } else { self.line_ends[i - 1] };
+        start..start + content_len(&self.buf.as_ref()[start..self.line_ends[i]])
+    }
+
+    /// The line at `i`, excluding the trailing newline.
+    fn line(&self, i: usize) -> &[u8] {
+        &self.buf.as_ref()[self.line_content(i)]
+    }
+
+    /// Check that the separator line starts with '+' (and if it repeats the title,
+    /// that it matches the header), that the sequence and quality have the same length,
+    /// and that all quality characters are in the valid Phred+33 range.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        let separator = self.line(2);
+        let Some(separator_title) = separator.strip_prefix(b"+") else {
+            return invalid(
+                "invalid FASTQ record: separator line does not start with '+'".to_string(),
+            );
+        };
+        let title = self.line(0).strip_prefix(b"@").unwrap_or(self.line(0));
+        if !separator_title.is_empty() && separator_title != title {
+            return invalid(format!(
+                "invalid FASTQ record: separator line title '{}' does not match header title '{}'",
+                String::from_utf8_lossy(separator_title),
+                String::from_utf8_lossy(title)
+            ));
+        }
+        let (sequence, quality) = (self.line(1), self.line(3));
+        if sequence.len() != quality.len() {
+            return invalid(format!(
+                "invalid FASTQ record: sequence length {} does not match quality length {}",
+                sequence.len(),
+                quality.len()
+            ));
+        }
+        if let Some(i) = quality
+            .iter()
+            .position(|q| !(MIN_QUALITY..=MAX_QUALITY).contains(q))
+        {
+            return invalid(format!(
+                "invalid FASTQ record: quality character {:?} at position {} is outside the valid range '{}' to '{}'",
+                quality[i] as char,
+                i + 1,
+                MIN_QUALITY as char,
+                MAX_QUALITY as char
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the separator line repeats the title of the header ('+' followed by the title).
+    pub fn separator_repeats_title(&self) -> bool {
+        let separator = self.line(2);
+        separator.len() > 1 && separator[0] == b'+' && separator.get(1..) == self.line(0).get(1..)
+    }
+
+    /// The complete record as it was read.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buf.as_ref()
+    }
+}
+
+impl<B: AsRef<[u8]>> AsRef<[u8]> for Record<B> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Record<B> {
+    pub fn header_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut()[..self.line_ends[0]]
+    }
+
+    /// The separator line, excluding the trailing newline.
+    pub fn separator_mut(&mut self) -> &mut [u8] {
+        let range = self.line_content(2);
+        &mut self.buf.as_mut()[range]
+    }
+
+    /// The sequence line, excluding the trailing newline.
+    pub fn sequence_mut(&mut self) -> &mut [u8] {
+        let range = self.line_content(1);
+        &mut self.buf.as_mut()[range]
+    }
+
+    /// The quality line, excluding the trailing newline.
+    pub fn quality_mut(&mut self) -> &mut [u8] {
+        let range = self.line_content(3);
+        &mut self.buf.as_mut()[range]
+    }
+}
+
+/// Position of a record in the (decompressed) input, for error messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Position {
+    /// 1-based record number
+    pub record: u64,
+    /// 1-based line number of the header of the record
+    pub line: u64,
+    /// byte offset of the start of the record
+    pub offset: u64,
+}
+
+impl Position {
+    /// Position of the first record.
+    pub fn new() -> Self {
+        Position {
+            record: 1,
+            line: 1,
+            offset: 0,
+        }
+    }
+
+    /// Advance to the record that follows `record`.
+    pub fn advance<B: AsRef<[u8]>>(&mut self, record: &Record<B>) {
+        self.advance_by(N_LINES_PER_RECORD as u64, record.as_bytes().len() as u64);
+    }
+
+    /// Advance to the record that follows a record of this many lines and bytes in the input.
+    pub fn advance_by(&mut self, lines: u64, bytes: u64) {
+        self.record += 1;
+        self.line += lines;
+        self.offset += bytes;
+    }
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "record {} (line {}, byte offset {})",
+            self.record, self.line, self.offset
+        )
+    }
+}
+
+/// Append one line (including the trailing '\n' if present) to line.
+/// Returns number of bytes read (0 on EOF).
+#[inline(always)]
+fn read_line<R: BufRead>(reader: &mut R, line: &mut Vec<u8>) -> io::Result<usize> {
+    let mut total = 0usize;
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(total);
+        }
+        if let Some(pos) = memchr(b'\n', available) {
+            // include newline
+            line.extend_from_slice(&available[..=pos]);
+            let consume = pos + 1;
+            reader.consume(consume);
+            total += consume;
+            return Ok(total);
+        } else {
+            // consume all
+            line.extend_from_slice(available);
+            let consume = available.len();
+            reader.consume(consume);
+            total += consume;
+        }
+    }
+}
+
+/// Read the next FASTQ record into record, erasing previous contents.
+/// Returns false on EOF, and an error if the record is truncated.
+pub fn read_record<R: BufRead>(reader: &mut R, record: &mut Record) -> io::Result<bool> {
+    record.buf.clear();
+    for i in 0..N_LINES_PER_RECORD {
+        if read_line(reader, &mut record.buf)? == 0 {
+            if i == 0 {
+                return Ok(false); // no header: EOF
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated FASTQ record (expected 4 lines)",
+            ));
+        }
+        record.line_ends[i] = record.buf.len();
+    }
+    Ok(true)
+}
+
+/// Length of a line without its trailing newline ('\n' or "\r\n").
+fn content_len(line: &[u8]) -> usize {
+    match line.strip_suffix(b"\n") {
+        Some(content) => content.strip_suffix(b"\r").unwrap_or(content).len(),
+        None => line.len(),
+    }
+}
+
+/// Remove the newline ('\n' or "\r\n") before the line that starts at `start` in buf
+/// (joining it to the line before), and return the new start of the line.
+fn join_line(buf: &mut Vec<u8>, start: usize) -> usize {
+    let newline = if buf[..start].ends_with(b"\r\n") {
+        2
+    } else {
+        1
+    };
+    buf.drain(start - newline..start);
+    start - newline
+}
+
+/// Read the next FASTQ record, whose sequence and quality may be wrapped over several lines,
+/// into record, erasing previous contents. The sequence lines end at the separator line
+/// (starting with '+'), and the quality lines end once they are as long as the sequence,
+/// as quality lines can also start with '@' or '+'. With `unwrap` the sequence and quality lines
+/// are joined into a single line each, otherwise the record is stored as it was read
+/// (and its sequence and quality include the newlines within them).
+/// Returns the number of lines and bytes read, None on EOF, and an error if the record is
+/// truncated or its quality is longer than its sequence.
+pub fn read_wrapped_record<R: BufRead>(
+    reader: &mut R,
+    record: &mut Record,
+    unwrap: bool,
+) -> io::Result<Option<(u64, u64)>> {
+    let truncated = |missing: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("truncated FASTQ record (missing {missing})"),
+        ))
+    };
+    record.buf.clear();
+    let mut bytes = read_line(reader, &mut record.buf)?;
+    if bytes == 0 {
+        return Ok(None);
+    }
+    record.line_ends[0] = record.buf.len();
+    let mut lines = 1;
+
+    let mut sequence_len = 0;
+    loop {
+        let mut start = record.buf.len();
+        let n = read_line(reader, &mut record.buf)?;
+        if n == 0 {
+            return truncated("separator line");
+        }
+        bytes += n;
+        lines += 1;
+        if record.buf[start] == b'+' {
+            record.line_ends[1] = start;
+            record.line_ends[2] = record.buf.len();
+            break;
+        }
+        if unwrap && start > record.line_ends[0] {
+            start = join_line(&mut record.buf, start);
+        }
+        sequence_len += content_len(&record.buf[start..]);
+    }
+
+    let mut quality_len = 0;
+    while quality_len < sequence_len || record.buf.len() == record.line_ends[2] {
+        let mut start = record.buf.len();
+        let n = read_line(reader, &mut record.buf)?;
+        if n == 0 {
+            return truncated("quality");
+        }
+        bytes += n;
+        lines += 1;
+        if unwrap && start > record.line_ends[2] {
+            start = join_line(&mut record.buf, start);
+        }
+        quality_len += content_len(&record.buf[start..]);
+    }
+    if quality_len > sequence_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "invalid FASTQ record: quality length {quality_len} is longer than sequence length {sequence_len}"
+            ),
+        ));
+    }
+    record.line_ends[3] = record.buf.len();
+    Ok(Some((lines, bytes as u64)))
+}
+
+/// Find the line ends of the complete FASTQ record at the start of data, without copying it.
+/// If `at_eof` is set, data is the rest of the input and the last line does not need a
+/// trailing newline (as for [`read_record`]). Returns None if data does not contain a complete record.
+pub fn find_record(data: &[u8], at_eof: bool) -> Option<[usize; N_LINES_PER_RECORD]> {
+    let mut line_ends = [0; N_LINES_PER_RECORD];
+    let mut start = 0;
+    for (i, end) in line_ends.iter_mut().enumerate() {
+        *end = match memchr(b'\n', &data[start..]) {
+            Some(pos) => start + pos + 1,
+            None if at_eof && i == N_LINES_PER_RECORD - 1 && start < data.len() => data.len(),
+            None => return None,
+        };
+        start = *end;
+    }
+    Some(line_ends)
+}
+
+/// The read name of a FASTQ header: everything after the '@' up to the first
+/// space, tab or newline, without any trailing `/1` or `/2` mate suffix.
+pub fn read_name(header: &[u8]) -> &[u8] {
+    let header = header.strip_prefix(b"@").unwrap_or(header);
+    let end = memchr2(b' ', b'\t', header)
+        .or_else(|| memchr(b'\n', header))
+        .unwrap_or(header.len());
+    let name = &header[..end];
+    match name {
+        [rest @ .., b'/', b'1' | b'2'] => rest,
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::illumina(
+        b"@VH00821:6:AACCCKLM5:1:1101:18231:1000 1:N:0:TCTTGAGGTT+ACTACTTGAG\n",
+        b"VH00821:6:AACCCKLM5:1:1101:18231:1000"
+    )]
+    #[case::tab(b"@r1\t1:N:0:AAAA+ACGT\n", b"r1")]
+    #[case::no_comment(b"@r1\n", b"r1")]
+    #[case::mate_suffix_1(b"@r1/1\n", b"r1")]
+    #[case::mate_suffix_2(b"@r1/2 1:N:0:AAAA+ACGT\n", b"r1")]
+    #[case::other_suffix(b"@r1/3\n", b"r1/3")]
+    #[case::no_newline(b"@r1", b"r1")]
+    fn read_name_valid(#[case] header: &[u8], #[case] expected: &[u8]) {
+        assert_eq!(
+            String::from_utf8_lossy(read_name(header)),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    fn record(text: &[u8]) -> Record {
+        let mut record = Record::new();
+        assert!(read_record(&mut &text[..], &mut record).unwrap());
+        record
+    }
+
+    #[rstest]
+    #[case::minimal(b"@r1\nACGT\n+\n!!!!\n")]
+    #[case::repeated_title(b"@r1 1:N:0:AAAA+ACGT\nACGT\n+r1 1:N:0:AAAA+ACGT\n!I~#\n")]
+    #[case::empty(b"@r1\n\n+\n\n")]
+    #[case::no_final_newline(b"@r1\nACGT\n+\n!!!!")]
+    #[case::crlf(b"@r1\r\nACGT\r\n+r1\r\n!!!!\r\n")]
+    fn validate_valid(#[case] text: &[u8]) {
+        record(text).validate().unwrap();
+    }
+
+    #[rstest]
+    #[case::no_plus(b"@r1\nACGT\n-\n!!!!\n", "separator line does not start with '+'")]
+    #[case::title_mismatch(
+        b"@r1\nACGT\n+r2\n!!!!\n",
+        "title 'r2' does not match header title 'r1'"
+    )]
+    #[case::short_quality(
+        b"@r1\nACGT\n+\n!!!\n",
+        "sequence length 4 does not match quality length 3"
+    )]
+    #[case::long_quality(
+        b"@r1\nACGT\n+\n!!!!!\n",
+        "sequence length 4 does not match quality length 5"
+    )]
+    #[case::space_quality(b"@r1\nACGT\n+\n!! !\n", "quality character ' ' at position 3")]
+    #[case::del_quality(
+        b"@r1\nACGT\n+\n!!!\x7f\n",
+        "quality character '\\u{7f}' at position 4"
+    )]
+    fn validate_invalid(#[case] text: &[u8], #[case] msg_substr: &str) {
+        let msg = record(text).validate().unwrap_err().to_string();
+        assert!(msg.contains(msg_substr), "{msg}");
+    }
+
+    #[rstest]
+    #[case::complete(b"@r1\nACGT\n+\n!!!!\n@r2", false, Some([4, 9, 11, 16]))]
+    #[case::incomplete(b"@r1\nACGT\n+\n!!!!", false, None)]
+    #[case::no_final_newline(b"@r1\nACGT\n+\n!!!!", true, Some([4, 9, 11, 15]))]
+    #[case::truncated(b"@r1\nACGT\n+\n", true, None)]
+    #[case::empty(b"", true, None)]
+    fn find_record_lines(
+        #[case] data: &[u8],
+        #[case] at_eof: bool,
+        #[case] expected: Option<[usize; N_LINES_PER_RECORD]>,
+    ) {
+        assert_eq!(find_record(data, at_eof), expected);
+    }
+
+    #[rstest]
+    #[case::not_wrapped(b"@r1\nACGT\n+\n!!!!\n", b"@r1\nACGT\n+\n!!!!\n", 4)]
+    #[case::wrapped(b"@r1\nACG\nTA\n+\n@+!\n!#\n", b"@r1\nACGTA\n+\n@+!!#\n", 6)]
+    #[case::separator_title(b"@r1\nAC\nG\n+r1\n!\n!!\n", b"@r1\nACG\n+r1\n!!!\n", 6)]
+    #[case::empty(b"@r1\n+\n\n", b"@r1\n+\n\n", 3)]
+    #[case::crlf(
+        b"@r1\r\nACG\r\nTA\r\n+\r\n@+!!\r\n#\r\n",
+        b"@r1\r\nACGTA\r\n+\r\n@+!!#\r\n",
+        6
+    )]
+    fn read_wrapped(
+        #[case] text: &[u8],
+        #[case] unwrapped: &[u8],
+        #[case] lines: u64,
+    ) -> io::Result<()> {
+        let mut input = [text, b"@r2\nA\n+\n#\n"].concat();
+        for unwrap in [false, true] {
+            let mut reader = &input[..];
+            let mut record = Record::new();
+            let size = read_wrapped_record(&mut reader, &mut record, unwrap)?;
+            assert_eq!(size, Some((lines, text.len() as u64)));
+            assert_eq!(record.as_bytes(), if unwrap { unwrapped } else { text });
+            assert_eq!(record.line(0), b"@r1");
+            if unwrap {
+                record.validate()?;
+            }
+            assert!(read_wrapped_record(&mut reader, &mut record, unwrap)?.is_some());
+            assert_eq!(record.as_bytes(), b"@r2\nA\n+\n#\n");
+            assert!(read_wrapped_record(&mut reader, &mut record, unwrap)?.is_none());
+        }
+        input.truncate(text.len() - 3);
+        let mut record = Record::new();
+        assert!(read_wrapped_record(&mut &input[..], &mut record, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_wrapped_long_quality() {
+        let mut record = Record::new();
+        let msg = read_wrapped_record(&mut &b"@r1\nAC\nG\n+\n!!\n!!\n"[..], &mut record, false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            msg.contains("quality length 4 is longer than sequence length 3"),
+            "{msg}"
+        );
+    }
+
+    #[rstest]
+    #[case::lf(b"@r1\nACGT\n+\n!!!!\n", Wrapping::None, b"@r1\nACGT\n+\n!!!!\n")]
+    #[case::crlf(
+        b"@r1\r\nACGT\r\n+\r\n!!!!\r\n",
+        Wrapping::None,
+        b"@r1\nACGT\n+\n!!!!\n"
+    )]
+    #[case::no_final_newline(b"@r1\r\nACGT\r\n+\r\n!!!!", Wrapping::None, b"@r1\nACGT\n+\n!!!!")]
+    #[case::mixed(
+        b"@r1\nACGT\r\n+\n!!\r!!\r\n",
+        Wrapping::None,
+        b"@r1\nACGT\n+\n!!\r!!\n"
+    )]
+    #[case::wrapped(
+        b"@r1\r\nAC\r\nGT\r\n+\r\n!!!\r\n!\r\n",
+        Wrapping::Keep,
+        b"@r1\nAC\nGT\n+\n!!!\n!\n"
+    )]
+    fn read_lf(
+        #[case] text: &[u8],
+        #[case] wrapping: Wrapping,
+        #[case] expected: &[u8],
+    ) -> io::Result<()> {
+        let options = ReadOptions {
+            wrapping,
+            line_endings: LineEndings::Lf,
+        };
+        let mut record = Record::new();
+        let size = options.read_record(&mut &text[..], &mut record)?;
+        assert_eq!(size.map(|(_, bytes)| bytes), Some(text.len() as u64));
+        assert_eq!(record.as_bytes(), expected);
+        assert_eq!(record.header(), b"@r1\n");
+        assert_eq!(record.line(2), b"+");
+        Ok(())
+    }
+
+    #[test]
+    fn read_record_lines() -> io::Result<()> {
+        let mut input = &b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n@r2\nA\n+\n#"[..];
+        let mut record = Record::new();
+        assert!(read_record(&mut input, &mut record)?);
+        assert_eq!(record.header(), b"@r1 1:N:0:AAAA+ACGT\n");
+        assert_eq!(record.sequence_mut(), b"ACGT");
+        assert_eq!(record.quality_mut(), b"!!!!");
+        assert_eq!(record.as_bytes(), b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n");
+        // missing final newline is accepted
+        assert!(read_record(&mut input, &mut record)?);
+        assert_eq!(record.as_bytes(), b"@r2\nA\n+\n#");
+        assert_eq!(record.quality_mut(), b"#");
+        assert!(!read_record(&mut input, &mut record)?);
+        Ok(())
+    }
+}
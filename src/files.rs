@@ -0,0 +1,307 @@
+use crate::bam;
+use crate::compression::{self, Compression};
+use crate::sam;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of the buffers used for reading and writing.
+pub const IO_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Path that refers to stdin (for inputs) or stdout (for outputs).
+pub const STDIO: &str = "-";
+
+/// Number of (decompressed) bytes of an input used to identify its format.
+const FORMAT_PREFIX_BYTES: usize = 16;
+
+/// Number of (decompressed) bytes of an input read when opening it, so that they are
+/// available to identify its format and the format of its first FASTQ header.
+const PEEK_BYTES: usize = 4096;
+
+/// Format of the (decompressed) data of an input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Fastq,
+    /// BAM, e.g. unaligned reads with the sample barcode in the BC tag
+    Bam,
+    /// SAM text, starting with a header line
+    Sam,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Fastq => "FASTQ",
+            Format::Bam => "BAM",
+            Format::Sam => "SAM",
+        }
+    }
+}
+
+/// Identify the format of an input from its first (decompressed) bytes.
+/// Returns an error for compressed or other binary data, which cannot be FASTQ.
+fn detect_format(prefix: &[u8]) -> io::Result<Format> {
+    if prefix.starts_with(&bam::MAGIC) {
+        Ok(Format::Bam)
+    } else if sam::is_header_line(prefix) {
+        Ok(Format::Sam)
+    } else if prefix
+        .iter()
+        .any(|&b| b.is_ascii_control() && !b"\t\n\r".contains(&b))
+    {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "input appears to be compressed or binary, not FASTQ",
+        ))
+    } else {
+        Ok(Format::Fastq)
+    }
+}
+
+/// Prefix the message of an error with the name of the file it came from.
+pub fn with_name(e: io::Error, name: &str) -> io::Error {
+    io::Error::new(e.kind(), format!("{name}: {e}"))
+}
+
+/// Return an error if `output` is the same file as one of the `inputs` (also through another
+/// path or a link), as creating the output would truncate the input before it is read.
+pub fn check_not_input(output: &Path, inputs: &[PathBuf]) -> io::Result<()> {
+    if output.as_os_str() == STDIO {
+        return Ok(());
+    }
+    if let Some(input) = inputs.iter().find(|input| same_file(output, input)) {
+        return Err(with_name(
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("output file is the same as input {}", input.display()),
+            ),
+            &output.display().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether two paths refer to the same existing file.
+fn same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (std::fs::metadata(a), std::fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        match (a.canonicalize(), b.canonicalize()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// A (transparently decompressed) input file or stdin.
+/// Use [`Input::error`] to prefix errors from reading or parsing with the name of the file.
+pub struct Input {
+    name: String,
+    format: Format,
+    reader: io::BufReader<Box<dyn Read + Send>>,
+}
+
+impl Input {
+    /// Open the FASTQ file `path` for reading, where `-` means stdin.
+    /// Returns an error if the input is not FASTQ.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let input = Self::open_any(path)?;
+        if input.format != Format::Fastq {
+            return Err(input.error(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "input appears to be {}, not FASTQ (only a single SAM or BAM input can be fixed, \
+                     or convert it first, e.g. with `samtools fastq`)",
+                    input.format.name()
+                ),
+            )));
+        }
+        Ok(input)
+    }
+
+    /// Open `path` for reading, where `-` means stdin, in any of the supported formats.
+    pub fn open_any(path: &Path) -> io::Result<Self> {
+        let (name, reader): (String, Box<dyn Read + Send>) = if path.as_os_str() == STDIO {
+            ("<stdin>".to_string(), Box::new(io::stdin()))
+        } else {
+            let name = path.display().to_string();
+            let file = File::open(path).map_err(|e| with_name(e, &name))?;
+            (name, Box::new(file))
+        };
+        let (format, reader) = compression::decompressed(reader)
+            .and_then(|reader| compression::peek(reader, PEEK_BYTES))
+            .and_then(|(prefix, reader)| {
+                let prefix = &prefix[..prefix.len().min(FORMAT_PREFIX_BYTES)];
+                Ok((detect_format(prefix)?, reader))
+            })
+            .map_err(|e| with_name(e, &name))?;
+        let reader: Box<dyn Read + Send> = Box::new(reader);
+        Ok(Input {
+            name,
+            format,
+            reader: io::BufReader::with_capacity(IO_BUFFER_BYTES, reader),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Prefix the message of an error with the name of this input.
+    pub fn error(&self, e: io::Error) -> io::Error {
+        with_name(e, &self.name)
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl BufRead for Input {
+    #[inline(always)]
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    #[inline(always)]
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+/// An (optionally compressed) output file or stdout.
+/// All I/O errors are prefixed with the name of the file.
+/// [`Output::finish`] must be called to complete the output.
+pub struct Output {
+    name: String,
+    writer: io::BufWriter<compression::Writer<Box<dyn Write>>>,
+}
+
+impl Output {
+    /// Create `path` for writing, where `-` means stdout.
+    /// If `compression` is not given it is inferred from the file extension.
+    /// A `.gzi` index requested in the options is written next to the file.
+    /// Options that do not apply to the compression are rejected.
+    pub fn create(
+        path: &Path,
+        compression: Option<Compression>,
+        options: &compression::Options,
+    ) -> io::Result<Self> {
+        let compression = compression.unwrap_or_else(|| Compression::from_path(path));
+        let name = if path.as_os_str() == STDIO {
+            "<stdout>".to_string()
+        } else {
+            path.display().to_string()
+        };
+        let unsupported =
+            |msg: &str| with_name(io::Error::new(io::ErrorKind::InvalidInput, msg), &name);
+        if options.zstd_window_log.is_some() && compression != Compression::Zstd {
+            return Err(unsupported(
+                "zstd long distance matching can only be used for zstd compressed output",
+            ));
+        }
+        if options.threads > 1 && !matches!(compression, Compression::Bgzf | Compression::Zstd) {
+            return Err(unsupported(
+                "multiple compression threads can only be used for BGZF or zstd compressed output",
+            ));
+        }
+        let index_path = if options.index {
+            if compression != Compression::Bgzf || path.as_os_str() == STDIO {
+                return Err(unsupported(
+                    "a .gzi index can only be written for a BGZF compressed output file",
+                ));
+            }
+            let mut index_path = path.as_os_str().to_owned();
+            index_path.push(".gzi");
+            Some(PathBuf::from(index_path))
+        } else {
+            None
+        };
+        let writer: Box<dyn Write> = if path.as_os_str() == STDIO {
+            let stdout = io::stdout().lock();
+            Box::new(io::BufWriter::with_capacity(IO_BUFFER_BYTES, stdout))
+        } else {
+            let file = File::create(path).map_err(|e| with_name(e, &name))?;
+            Box::new(io::BufWriter::with_capacity(IO_BUFFER_BYTES, file))
+        };
+        let writer = compression::Writer::new(writer, compression, options, index_path)
+            .map_err(|e| with_name(e, &name))?;
+        Ok(Output {
+            name,
+            writer: io::BufWriter::with_capacity(IO_BUFFER_BYTES, writer),
+        })
+    }
+
+    /// Flush all buffered data and complete the compressed stream if any.
+    pub fn finish(self) -> io::Result<()> {
+        let name = self.name;
+        self.writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|w| w.finish())
+            .map_err(|e| with_name(e, &name))
+    }
+}
+
+impl Write for Output {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf).map_err(|e| with_name(e, &self.name))
+    }
+
+    #[inline(always)]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer
+            .write_all(buf)
+            .map_err(|e| with_name(e, &self.name))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush().map_err(|e| with_name(e, &self.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::empty(b"", Some(Format::Fastq))]
+    #[case::short_text(b"@r", Some(Format::Fastq))]
+    #[case::fastq(b"@r1 1:N:0:AAAA+ACGT\r\nACGT\t", Some(Format::Fastq))]
+    #[case::bam(b"BAM\x01\x00\x00\x00\x00", Some(Format::Bam))]
+    #[case::fastq_tab_comment(b"@A1\tBC:Z:ACGT+TTGA\n", Some(Format::Fastq))]
+    #[case::sam(b"@HD\tVN:1.6\tSO:unsorted\n", Some(Format::Sam))]
+    #[case::binary(b"PK\x03\x04\x14\x00", None)]
+    fn formats(#[case] prefix: &[u8], #[case] format: Option<Format>) {
+        assert_eq!(detect_format(prefix).ok(), format);
+    }
+
+    #[test]
+    fn output_is_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("x.fastq");
+        std::fs::write(&input, b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n").unwrap();
+        let inputs = [PathBuf::from(STDIO), input.clone()];
+        let msg = check_not_input(&dir.path().join(".").join("x.fastq"), &inputs)
+            .unwrap_err()
+            .to_string();
+        assert!(msg.contains("output file is the same as input"), "{msg}");
+        check_not_input(&dir.path().join("y.fastq"), &inputs).unwrap();
+        check_not_input(Path::new(STDIO), &inputs).unwrap();
+    }
+}
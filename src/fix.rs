@@ -0,0 +1,539 @@
+use crate::chunk::ChunkReader;
+use crate::dna::reverse_complement_in_place;
+use crate::fastq::{self, Position, ReadOptions, Record};
+use crate::files::{self, Input, Output};
+use crate::header::IndexLocation;
+use crate::policy::ErrorHandler;
+use crate::report::Stats;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Number of records (of each input) in a chunk that is rewritten by one worker thread.
+const CHUNK_RECORDS: usize = 4096;
+
+/// Which parts of a FASTQ record to rewrite.
+#[derive(Clone, Copy)]
+pub struct Fix<'a> {
+    /// Reverse-complement the i5 part of the header
+    pub header: bool,
+    /// Reverse-complement the sequence and reverse the quality (for an I2 index read)
+    pub index_read: bool,
+    /// Validate the separator, sequence and quality lines before rewriting the record
+    pub strict: bool,
+    /// Where the i7 and i5 are in the header
+    pub location: &'a IndexLocation,
+}
+
+impl Fix<'_> {
+    /// Rewrite the record. If this returns an error, the record is unchanged.
+    /// Applying the same fix twice restores the original record.
+    pub fn apply<B: AsRef<[u8]> + AsMut<[u8]>>(self, record: &mut Record<B>) -> io::Result<()> {
+        let i5 = self.locate(record)?;
+        self.rewrite(record, i5);
+        Ok(())
+    }
+
+    /// Check the record without changing it: validate it (with `strict`) and find the range
+    /// of the i5 in its header (with `header`), to be passed to [`Fix::rewrite`].
+    pub fn locate<B: AsRef<[u8]>>(self, record: &Record<B>) -> io::Result<Option<Range<usize>>> {
+        if self.strict {
+            record.validate()?;
+        }
+        if self.header {
+            let (_, i5) = self.location.index_field(record.header())?;
+            return Ok(Some(i5));
+        }
+        Ok(None)
+    }
+
+    /// Rewrite a record that was checked by [`Fix::locate`], which returned `i5`.
+    /// The i5 is also reverse-complemented in a separator line that repeats the title.
+    pub fn rewrite<B: AsRef<[u8]> + AsMut<[u8]>>(
+        self,
+        record: &mut Record<B>,
+        i5: Option<Range<usize>>,
+    ) {
+        if let Some(i5) = i5 {
+            if record.separator_repeats_title() {
+                reverse_complement_in_place(&mut record.separator_mut()[i5.clone()]);
+            }
+            reverse_complement_in_place(&mut record.header_mut()[i5]);
+        }
+        if self.index_read {
+            reverse_complement_in_place(record.sequence_mut());
+            record.quality_mut().reverse();
+        }
+    }
+}
+
+/// Label an error with the input name and the position of the record in the input.
+pub fn record_error(name: &str, position: &Position, e: io::Error) -> io::Error {
+    files::with_name(files::with_name(e, &position.to_string()), name)
+}
+
+/// The error for paired inputs with different numbers of records, where `name` ran out first.
+fn missing_record(name: &str, record_number: u64) -> io::Error {
+    files::with_name(
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("missing paired record {record_number}: paired inputs have different numbers of records"),
+        ),
+        name,
+    )
+}
+
+/// Check that the read names in the headers of paired records agree.
+fn check_read_names<'a>(
+    record_number: u64,
+    names: &[String],
+    headers: impl Iterator<Item = &'a [u8]>,
+) -> io::Result<()> {
+    let mut read_names = headers.map(fastq::read_name);
+    let Some(first) = read_names.next() else {
+        return Ok(());
+    };
+    for (mate_name, input) in read_names.zip(&names[1..]) {
+        if mate_name != first {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "read names of paired record {record_number} differ: '{}' in {} but '{}' in {}",
+                    String::from_utf8_lossy(first),
+                    names[0],
+                    String::from_utf8_lossy(mate_name),
+                    input,
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// One record from each input, read in lockstep.
+struct Mates {
+    records: Vec<Record>,
+    /// position of each record in its input
+    positions: Vec<Position>,
+    /// error from rewriting one of the records, in which case all records are unchanged
+    error: Option<io::Error>,
+}
+
+impl Mates {
+    fn new(n_inputs: usize) -> Self {
+        Mates {
+            records: (0..n_inputs).map(|_| Record::new()).collect(),
+            positions: vec![Position::new(); n_inputs],
+            error: None,
+        }
+    }
+
+    /// Apply the fix of each input to its record. With `same_i5`, first check that the i5
+    /// in the headers of all records are the same (and so they are afterwards as well).
+    /// If any record cannot be rewritten, all records are left unchanged and the error is stored.
+    fn fix(&mut self, fixes: &[Fix], names: &[String], same_i5: bool) {
+        self.error = None;
+        if same_i5 {
+            if let Err(e) = self.check_same_i5(fixes[0].location) {
+                let last = self.records.len() - 1;
+                self.error = Some(record_error(&names[last], &self.positions[last], e));
+                return;
+            }
+        }
+        let mut i5s = Vec::with_capacity(self.records.len());
+        for (i, (record, fix)) in self.records.iter().zip(fixes).enumerate() {
+            match fix.locate(record) {
+                Ok(i5) => i5s.push(i5),
+                Err(e) => {
+                    self.error = Some(record_error(&names[i], &self.positions[i], e));
+                    return;
+                }
+            }
+        }
+        for ((record, fix), i5) in self.records.iter_mut().zip(fixes).zip(i5s) {
+            fix.rewrite(record, i5);
+        }
+    }
+
+    /// Check that the i5 in the (valid) headers of all records are the same.
+    fn check_same_i5(&self, location: &IndexLocation) -> io::Result<()> {
+        let i5s = self.records.iter().map(|record| {
+            let header = record.header();
+            location.index_field(header).map(|(_, i5)| &header[i5])
+        });
+        let i5s = i5s.collect::<io::Result<Vec<_>>>()?;
+        if let Some(other) = i5s.iter().find(|&i5| i5 != &i5s[0]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "i5 of mates differ: '{}' and '{}'",
+                    String::from_utf8_lossy(i5s[0]),
+                    String::from_utf8_lossy(other),
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reads records from several inputs in lockstep, checking that they belong together.
+/// With a single input and more than one record per set of mates, the mates are read
+/// one after the other from the same (interleaved) input.
+struct MateReader<'a> {
+    inputs: &'a mut [Input],
+    /// name of the input of each mate
+    names: &'a [String],
+    /// position of the next record in each input
+    positions: Vec<Position>,
+    options: ReadOptions,
+}
+
+impl<'a> MateReader<'a> {
+    fn new(inputs: &'a mut [Input], names: &'a [String], options: ReadOptions) -> Self {
+        let positions = vec![Position::new(); inputs.len()];
+        MateReader {
+            inputs,
+            names,
+            positions,
+            options,
+        }
+    }
+
+    /// Number of sets of mates read so far.
+    fn records(&self) -> u64 {
+        let per_input = (self.names.len() / self.inputs.len()) as u64;
+        (self.positions[0].record - 1) / per_input
+    }
+
+    /// Read the next record of each mate into mates. Returns false at the end of the inputs,
+    /// and an error if a record is truncated, an input runs out of records early,
+    /// or the read names of the mates differ.
+    fn read(&mut self, mates: &mut Mates) -> io::Result<bool> {
+        let record_number = self.records() + 1;
+        let n = mates.records.len();
+        let mut ended = Vec::new();
+        for (i, (record, position)) in mates
+            .records
+            .iter_mut()
+            .zip(&mut mates.positions)
+            .enumerate()
+        {
+            let input = i * self.inputs.len() / n;
+            position.clone_from(&self.positions[input]);
+            if let Some((lines, bytes)) = self
+                .options
+                .read_record(&mut self.inputs[input], record)
+                .map_err(|e| record_error(self.inputs[input].name(), position, e))?
+            {
+                self.positions[input].advance_by(lines, bytes);
+            } else {
+                ended.push(i);
+            }
+        }
+        if ended.len() == n {
+            return Ok(false);
+        }
+        if let Some(&ended) = ended.first() {
+            if self.inputs.len() == 1 {
+                return Err(files::with_name(
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("missing mate of interleaved record {record_number}: odd number of records"),
+                    ),
+                    self.inputs[0].name(),
+                ));
+            }
+            return Err(missing_record(&self.names[ended], record_number));
+        }
+        check_read_names(
+            record_number,
+            self.names,
+            mates.records.iter().map(Record::header),
+        )
+        .map(|_| true)
+    }
+}
+
+/// Writes rewritten records to the outputs, and passes invalid ones to the error handler.
+struct MateWriter<'a> {
+    outputs: &'a mut [Output],
+    errors: &'a mut ErrorHandler,
+    stats: Option<&'a mut Stats>,
+    names: &'a [String],
+    /// where the index is in the headers, for stats
+    location: &'a IndexLocation,
+}
+
+impl MateWriter<'_> {
+    fn write(&mut self, mates: &mut Mates) -> io::Result<()> {
+        if let Some(e) = mates.error.take() {
+            // there is one output per input, with one or more (interleaved) mates each
+            let per_input = (mates.records.len() / self.outputs.len()) as u64;
+            let record_number = (mates.positions[0].record - 1) / per_input + 1;
+            return self
+                .errors
+                .handle(e, record_number, &mates.records, self.outputs);
+        }
+        if let Some(stats) = self.stats.as_deref_mut() {
+            stats
+                .add(mates.records[0].header(), self.location)
+                .map_err(|e| record_error(&self.names[0], &mates.positions[0], e))?;
+        }
+        let n = mates.records.len();
+        for (i, record) in mates.records.iter().enumerate() {
+            self.outputs[i * self.outputs.len() / n].write_all(record.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Read FASTQ records from one or more inputs in lockstep (e.g. the R1 and R2 files of a
+/// paired-end run), rewrite each record with the fix of its input (by default reverse-complementing
+/// the i5 in the header), and write them to the corresponding outputs.
+/// Returns an error if the read names of the mates differ or an input runs out of records early.
+/// If any mate cannot be rewritten, all mates are passed unchanged to `errors`.
+/// Rewritten records of the first input are counted in stats if given.
+/// With more than one thread, chunks of records are rewritten in parallel by that many
+/// worker threads, and written in the same order as they were read.
+/// Records are read with the given `options` (their line layout and line endings).
+/// Returns the number of records read from each input.
+pub fn fix_i5(
+    inputs: &mut [Input],
+    outputs: &mut [Output],
+    fixes: &[Fix],
+    errors: &mut ErrorHandler,
+    stats: Option<&mut Stats>,
+    threads: usize,
+    options: ReadOptions,
+) -> io::Result<u64> {
+    let names: Vec<String> = inputs
+        .iter()
+        .map(|input| input.name().to_string())
+        .collect();
+    if threads <= 1 && options == ReadOptions::default() {
+        return fix_i5_in_place(inputs, outputs, fixes, errors, stats, &names);
+    }
+    let mut reader = MateReader::new(inputs, &names, options);
+    let mut writer = MateWriter {
+        outputs,
+        errors,
+        stats,
+        names: &names,
+        location: fixes[0].location,
+    };
+    fix_mates(&mut reader, &mut writer, fixes, &names, false, threads)?;
+    Ok(reader.records())
+}
+
+/// Read interleaved FASTQ records (R1, R2, R1, R2, ...) in pairs from input, rewrite both
+/// headers, and write them to output. Returns an error if the read names of the mates differ,
+/// and passes a pair to `errors` if either mate cannot be rewritten, or the i5 of the mates
+/// differ. Rewritten pairs are counted (once) in stats if given, and rewritten in parallel by
+/// `threads` worker threads as in [`fix_i5`].
+/// Returns the number of pairs read.
+pub fn fix_interleaved(
+    input: &mut Input,
+    output: &mut Output,
+    fix: Fix,
+    errors: &mut ErrorHandler,
+    stats: Option<&mut Stats>,
+    threads: usize,
+    options: ReadOptions,
+) -> io::Result<u64> {
+    let names = vec![input.name().to_string(); 2];
+    let fixes = [fix; 2];
+    let mut reader = MateReader::new(std::slice::from_mut(input), &names, options);
+    let mut writer = MateWriter {
+        outputs: std::slice::from_mut(output),
+        errors,
+        stats,
+        names: &names,
+        location: fix.location,
+    };
+    fix_mates(&mut reader, &mut writer, &fixes, &names, true, threads)?;
+    Ok(reader.records())
+}
+
+/// Read, rewrite and write all sets of mates, in this thread or with [`fix_i5_threaded`].
+fn fix_mates(
+    reader: &mut MateReader,
+    writer: &mut MateWriter,
+    fixes: &[Fix],
+    names: &[String],
+    same_i5: bool,
+    threads: usize,
+) -> io::Result<()> {
+    if threads > 1 {
+        return fix_i5_threaded(reader, writer, fixes, names, same_i5, threads);
+    }
+    let mut mates = Mates::new(names.len());
+    while reader.read(&mut mates)? {
+        mates.fix(fixes, names, same_i5);
+        writer.write(&mut mates)?;
+    }
+    Ok(())
+}
+
+/// The single-threaded version of [`fix_i5`], which rewrites the records in place in the
+/// buffer of a [`ChunkReader`] for each input and writes them from there.
+fn fix_i5_in_place(
+    inputs: &mut [Input],
+    outputs: &mut [Output],
+    fixes: &[Fix],
+    errors: &mut ErrorHandler,
+    mut stats: Option<&mut Stats>,
+    names: &[String],
+) -> io::Result<u64> {
+    let mut readers: Vec<ChunkReader<&mut Input>> =
+        inputs.iter_mut().map(ChunkReader::new).collect();
+    loop {
+        let mut ended = None;
+        let mut n_ended = 0;
+        for (i, (reader, output)) in readers.iter_mut().zip(outputs.iter_mut()).enumerate() {
+            if !reader
+                .next_record(output)
+                .map_err(|e| record_error(&names[i], reader.position(), e))?
+            {
+                ended.get_or_insert(i);
+                n_ended += 1;
+            }
+        }
+        if n_ended == readers.len() {
+            break;
+        }
+        let record_number = readers[0].position().record;
+        if let Some(ended) = ended {
+            return Err(missing_record(&names[ended], record_number));
+        }
+        check_read_names(
+            record_number,
+            names,
+            readers.iter().map(ChunkReader::header),
+        )?;
+
+        // locate the i5 of all mates before rewriting any of them
+        let located = readers
+            .iter()
+            .zip(fixes)
+            .enumerate()
+            .map(|(i, (reader, fix))| fix.locate(&reader.record()).map_err(|e| (i, e)))
+            .collect::<Result<Vec<_>, _>>();
+        match located {
+            Ok(i5s) => {
+                for ((reader, fix), i5) in readers.iter_mut().zip(fixes).zip(i5s) {
+                    fix.rewrite(&mut reader.record_mut(), i5);
+                }
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats
+                        .add(readers[0].header(), fixes[0].location)
+                        .map_err(|e| record_error(&names[0], readers[0].position(), e))?;
+                }
+            }
+            Err((i, e)) => {
+                let e = record_error(&names[i], readers[i].position(), e);
+                for (reader, output) in readers.iter_mut().zip(outputs.iter_mut()) {
+                    reader.cut(output)?;
+                }
+                let records: Vec<_> = readers.iter().map(ChunkReader::record).collect();
+                errors.handle(e, readers[0].position().record, &records, outputs)?;
+            }
+        }
+    }
+    for (reader, output) in readers.iter_mut().zip(outputs.iter_mut()) {
+        reader.flush(output)?;
+    }
+    Ok(readers[0].position().record - 1)
+}
+
+/// The multi-threaded version of [`fix_i5`]: a reader thread splits the input into numbered
+/// chunks of records, worker threads rewrite them, and this thread writes them in order.
+/// With `same_i5` the i5 of all mates must be the same (see [`Mates::fix`]).
+fn fix_i5_threaded(
+    reader: &mut MateReader,
+    writer: &mut MateWriter,
+    fixes: &[Fix],
+    names: &[String],
+    same_i5: bool,
+    threads: usize,
+) -> io::Result<()> {
+    let (read_tx, read_rx) = mpsc::sync_channel::<(u64, Vec<Mates>)>(threads);
+    let read_rx = Arc::new(Mutex::new(read_rx));
+    let (fixed_tx, fixed_rx) = mpsc::sync_channel::<(u64, Vec<Mates>)>(threads);
+    // chunks that have been written are returned to the reader to reuse their buffers
+    let (free_tx, free_rx) = mpsc::channel::<Vec<Mates>>();
+
+    thread::scope(|scope| {
+        let reading = scope.spawn(move || -> io::Result<()> {
+            let mut index = 0;
+            loop {
+                let mut chunk = free_rx.try_recv().unwrap_or_default();
+                let mut n = 0;
+                let more = loop {
+                    if n == CHUNK_RECORDS {
+                        break Ok(true);
+                    }
+                    if chunk.len() == n {
+                        chunk.push(Mates::new(names.len()));
+                    }
+                    match reader.read(&mut chunk[n]) {
+                        Ok(true) => n += 1,
+                        Ok(false) => break Ok(false),
+                        Err(e) => break Err(e),
+                    }
+                };
+                chunk.truncate(n);
+                // sending fails if writing has failed, then the rest of the input is not needed
+                if n > 0 && read_tx.send((index, chunk)).is_err() {
+                    return Ok(());
+                }
+                if !more? {
+                    return Ok(());
+                }
+                index += 1;
+            }
+        });
+
+        for _ in 0..threads {
+            let read_rx = Arc::clone(&read_rx);
+            let fixed_tx = fixed_tx.clone();
+            scope.spawn(move || loop {
+                let Ok((index, mut chunk)) = read_rx.lock().unwrap().recv() else {
+                    return;
+                };
+                for mates in &mut chunk {
+                    mates.fix(fixes, names, same_i5);
+                }
+                if fixed_tx.send((index, chunk)).is_err() {
+                    return;
+                }
+            });
+        }
+        // the channels are closed once the reader and all workers are done
+        drop((read_rx, fixed_tx));
+
+        let mut write = || -> io::Result<()> {
+            let mut pending = HashMap::new();
+            let mut next = 0;
+            for (index, chunk) in &fixed_rx {
+                pending.insert(index, chunk);
+                while let Some(mut chunk) = pending.remove(&next) {
+                    for mates in &mut chunk {
+                        writer.write(mates)?;
+                    }
+                    // the reader may already be done
+                    let _ = free_tx.send(chunk);
+                    next += 1;
+                }
+            }
+            Ok(())
+        };
+        let written = write();
+        // if writing failed, this stops the workers, and in turn the reader
+        drop(fixed_rx);
+        let read = reading.join().expect("reader thread panicked");
+        // an error while writing is about an earlier record than one while reading
+        written.and(read)
+    })
+}
@@ -0,0 +1,530 @@
+use memchr::{memchr, memchr2, memchr2_iter, memrchr, memrchr2};
+use regex::bytes::Regex;
+use std::io::{self, BufRead};
+use std::ops::Range;
+use std::sync::LazyLock;
+
+/// Read names of MGI / DNBSEQ records: `<flowcell>L<lane>C<column>R<row><number>`,
+/// with an optional mate suffix.
+static MGI_READ_NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^@[A-Z0-9]+L[0-9]{1,2}C[0-9]{3}R[0-9]{3}[0-9]+(/[0-9])?$").unwrap()
+});
+
+/// Tag holding the barcodes in SAM-style header comments.
+const BARCODE_TAG: &str = "BC";
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Conventions of sequencer vendors for the index in FASTQ headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HeaderFormat {
+    /// Illumina (also Element AVITI and Singular Genomics): `@<name> 1:N:0:<i7>+<i5>`,
+    /// or CASAVA 1.7: `@<name>#<i7>+<i5>/1`
+    #[default]
+    Illumina,
+    /// MGI / DNBSEQ: `@<flowcell>L<lane>C<column>R<row><number>/1 <i7>+<i5>`, with the barcodes
+    /// separated by '+' or '-' in a `BC:Z:` comment tag, or else in the first comment field
+    /// with a separator (after its last ':', if any)
+    Mgi,
+    /// Ultima Genomics: the barcodes `<i7>-<i5>` in a `BC:Z:` comment tag,
+    /// as written by `samtools fastq -T BC` from unaligned BAM or CRAM
+    Ultima,
+}
+
+impl HeaderFormat {
+    /// Identify the format of a FASTQ header line, or None if it has no index in a known format.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        let name = comment_fields(header)
+            .next()
+            .map_or(header, |name| &header[name]);
+        if MGI_READ_NAME.is_match(name) {
+            Some(HeaderFormat::Mgi)
+        } else if index_field(header).is_ok() {
+            Some(HeaderFormat::Illumina)
+        } else if tag_field(header, BARCODE_TAG).is_some() {
+            Some(HeaderFormat::Ultima)
+        } else {
+            None
+        }
+    }
+
+    /// Locate the i7 and i5 parts of the index field of a FASTQ header line in this format.
+    /// Returns the ranges of i7 and i5 within the header, or an error if the header is invalid.
+    pub fn index_field(self, header: &[u8]) -> io::Result<(Range<usize>, Range<usize>)> {
+        let field = match self {
+            HeaderFormat::Illumina => return index_field(header),
+            HeaderFormat::Mgi => {
+                check_header(header)?;
+                tag_field(header, BARCODE_TAG)
+                    .or_else(|| {
+                        comment_fields(header)
+                            .skip(1)
+                            .find(|field| memchr2(b'+', b'-', &header[field.clone()]).is_some())
+                    })
+                    .ok_or_else(|| {
+                        invalid(
+                            "invalid FASTQ header: missing barcodes after the read name"
+                                .to_string(),
+                        )
+                    })?
+            }
+            HeaderFormat::Ultima => {
+                check_header(header)?;
+                tag_field(header, BARCODE_TAG).ok_or_else(|| {
+                    invalid(format!("invalid FASTQ header: missing {BARCODE_TAG} tag"))
+                })?
+            }
+        };
+        split_index(header, field)
+    }
+}
+
+/// Detect the header format from the first header line of a FASTQ input, without consuming it.
+/// Returns the Illumina format if it is not recognized.
+pub fn detect_format<R: BufRead>(reader: &mut R) -> io::Result<HeaderFormat> {
+    let buf = reader.fill_buf()?;
+    let mut header = buf[..memchr(b'\n', buf).map_or(buf.len(), |i| i + 1)].to_vec();
+    if !header.ends_with(b"\n") {
+        header.push(b'\n');
+    }
+    Ok(HeaderFormat::detect(&header).unwrap_or_default())
+}
+
+/// Where the index field `<i7>+<i5>` is in a FASTQ header line.
+#[derive(Clone, Debug)]
+pub enum IndexLocation {
+    /// Where the header format puts it
+    Format(HeaderFormat),
+    /// After the last ':' (if any) of this whitespace-separated comment field,
+    /// where 1 is the first field after the read name
+    Field(usize),
+    /// After the last ':' of the comment field starting with this tag and a ':'
+    /// (e.g. `BC:Z:<i7>+<i5>` for tag `BC`)
+    Tag(String),
+    /// The named groups `i7` (optional) and `i5` of the first match of this regular expression
+    Regex(Regex),
+}
+
+impl Default for IndexLocation {
+    fn default() -> Self {
+        IndexLocation::Format(HeaderFormat::Illumina)
+    }
+}
+
+impl IndexLocation {
+    /// Locate the i7 and i5 parts of the index field of a FASTQ header line.
+    /// Except in the Illumina format, i7 and i5 can also be separated by '-',
+    /// and the header can continue after them.
+    /// Returns the ranges of i7 and i5 within the header, or an error if the header is invalid.
+    pub fn index_field(&self, header: &[u8]) -> io::Result<(Range<usize>, Range<usize>)> {
+        let field = match self {
+            IndexLocation::Format(format) => return format.index_field(header),
+            IndexLocation::Field(n) => {
+                check_header(header)?;
+                comment_fields(header).nth(*n).ok_or_else(|| {
+                    invalid(format!("invalid FASTQ header: missing comment field {n}"))
+                })?
+            }
+            IndexLocation::Tag(tag) => {
+                check_header(header)?;
+                tag_field(header, tag)
+                    .ok_or_else(|| invalid(format!("invalid FASTQ header: missing {tag} tag")))?
+            }
+            IndexLocation::Regex(regex) => {
+                check_header(header)?;
+                let content = header.strip_suffix(b"\n").unwrap_or(header);
+                let content = content.strip_suffix(b"\r").unwrap_or(content);
+                let captures = regex.captures(content).ok_or_else(|| {
+                    invalid("invalid FASTQ header: does not match the index regex".to_string())
+                })?;
+                let Some(i5) = captures.name("i5") else {
+                    return Err(invalid(
+                        "invalid FASTQ header: no i5 in the index regex match".to_string(),
+                    ));
+                };
+                let i5 = i5.range();
+                let i7 = captures
+                    .name("i7")
+                    .map_or(i5.start..i5.start, |i7| i7.range());
+                return Ok((i7, i5));
+            }
+        };
+        split_index(header, field)
+    }
+}
+
+/// Split the index in a field of a header (after its last ':', if any) into i7 and i5
+/// at the last '+' or '-'.
+fn split_index(header: &[u8], field: Range<usize>) -> io::Result<(Range<usize>, Range<usize>)> {
+    let start = memrchr(b':', &header[field.clone()]).map_or(field.start, |i| field.start + i + 1);
+    let Some(separator) = memrchr2(b'+', b'-', &header[start..field.end]) else {
+        return Err(invalid(
+            "invalid FASTQ header: missing '+' in index field".to_string(),
+        ));
+    };
+    let separator = start + separator;
+    Ok((start..separator, separator + 1..field.end))
+}
+
+/// The comment field of a FASTQ header line starting with `tag` and a ':'.
+fn tag_field(header: &[u8], tag: &str) -> Option<Range<usize>> {
+    comment_fields(header).skip(1).find(|field| {
+        header[field.clone()]
+            .strip_prefix(tag.as_bytes())
+            .is_some_and(|rest| rest.starts_with(b":"))
+    })
+}
+
+/// Ranges of the whitespace-separated fields of a FASTQ header line (without its newline),
+/// the first one being the read name. Consecutive whitespace separates empty fields.
+fn comment_fields(header: &[u8]) -> impl Iterator<Item = Range<usize>> + '_ {
+    let content = header.strip_suffix(b"\n").unwrap_or(header);
+    let content = content.strip_suffix(b"\r").unwrap_or(content);
+    let mut start = 0;
+    memchr2_iter(b' ', b'\t', content)
+        .chain([content.len()])
+        .map(move |end| {
+            let field = start..end;
+            start = end + 1;
+            field
+        })
+}
+
+/// Check that a FASTQ header line starts with '@' and ends with a newline.
+fn check_header(header: &[u8]) -> io::Result<()> {
+    if header.is_empty() || header[0] != b'@' {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid FASTQ header: does not start with '@'",
+        ));
+    }
+
+    if header.last() != Some(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid FASTQ header: missing trailing newline",
+        ));
+    }
+    Ok(())
+}
+
+/// Locate the i7 and i5 parts of the index field of a FASTQ header line.
+/// Header is expected to start with '@' and end with ":i7+i5\n" (or ":i7+i5\r\n"),
+/// or with "#i7+i5/1\n" (CASAVA 1.7, where i5 is empty with a single index).
+/// Returns the ranges of i7 and i5 within the header, or an error if the header is invalid
+pub fn index_field(header: &[u8]) -> io::Result<(Range<usize>, Range<usize>)> {
+    check_header(header)?;
+
+    // Find last ':' in the header.
+    let Some(colon_index) = memrchr(b':', header) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid FASTQ header: missing ':' before index field",
+        ));
+    };
+    let after_colon_index = colon_index + 1;
+
+    // CASAVA 1.7 headers end with "#i7+i5/1" (or "#i7/1" with a single index) instead,
+    // where the mate suffix is optional
+    let mut content_end = header.len() - 1;
+    if header[..content_end].ends_with(b"\r") {
+        content_end -= 1;
+    }
+    if let Some(relative_hash_index) = memchr(b'#', &header[after_colon_index..content_end]) {
+        let start = after_colon_index + relative_hash_index + 1;
+        let end = memrchr(b'/', &header[start..content_end]).map_or(content_end, |i| start + i);
+        let plus = memchr(b'+', &header[start..end]).map_or(end, |i| start + i);
+        return Ok((start..plus, (plus + 1).min(end)..end));
+    }
+
+    // Find '+' after that last ':'.
+    let Some(relative_plus_index) = memchr(b'+', &header[after_colon_index..]) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid FASTQ header: missing '+' in index field",
+        ));
+    };
+    let plus_index = after_colon_index + relative_plus_index;
+
+    // i5 header is everything after '+' excluding the final newline characters
+    let mut stop_h5_index = header.len() - 1; // exclude final '\n'
+    if header[..stop_h5_index].ends_with(b"\r") {
+        stop_h5_index -= 1;
+    }
+    Ok((after_colon_index..plus_index, plus_index + 1..stop_h5_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dna::reverse_complement_in_place;
+    use rstest::rstest;
+
+    /// Reverse-complement the i5 part of a FASTQ header line in-place.
+    fn rewrite_i5(location: &IndexLocation, header: &mut [u8]) -> io::Result<()> {
+        let (_, i5) = location.index_field(header)?;
+        reverse_complement_in_place(&mut header[i5]);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::actacttgag(
+        b"@VH00821:6:AACCCKLM5:1:1101:18231:1000 1:N:0:TCTTGAGGTT+ACTACTTGAG\n",
+        b"@VH00821:6:AACCCKLM5:1:1101:18231:1000 1:N:0:TCTTGAGGTT+CTCAAGTAGT\n"
+    )]
+    #[case::a(b"@r1 1:N:0:AAAA+A\n", b"@r1 1:N:0:AAAA+T\n")]
+    #[case::ac(b"@r1 1:N:0:AAAA+AC\n", b"@r1 1:N:0:AAAA+GT\n")]
+    #[case::acg(b"@r1 1:N:0:AAAA+ACG\n", b"@r1 1:N:0:AAAA+CGT\n")]
+    #[case::acgt(b"@r1 1:N:0:AAAA+ACGT\n", b"@r1 1:N:0:AAAA+ACGT\n")]
+    #[case::acgt_lowercase(b"@r2 1:N:0:CCCC+acgt\n", b"@r2 1:N:0:CCCC+acgt\n")]
+    #[case::nnnn(b"@r3 1:N:0:GGGG+NnNn\n", b"@r3 1:N:0:GGGG+nNnN\n")]
+    #[case::actg_mixedcase(b"@r4 1:N:0:TTTT+AcTg\n", b"@r4 1:N:0:TTTT+cAgT\n")]
+    #[case::unknown_chars_are_left_unchanged(b"@r4 1:N:0:TTTT+xyZY\n", b"@r4 1:N:0:TTTT+YZyx\n")]
+    #[case::extra_colons(
+        b"@inst:run:flow:lane:tile:x:y 1:N:0:AAAA+TTTT\n",
+        b"@inst:run:flow:lane:tile:x:y 1:N:0:AAAA+AAAA\n"
+    )]
+    #[case::empty_i5(b"@pyt1 1:N:0:AAAA+\n", b"@pyt1 1:N:0:AAAA+\n")]
+    #[case::single_a(b"@pyt2 1:N:0:AAAA+A\n", b"@pyt2 1:N:0:AAAA+T\n")]
+    #[case::single_n(b"@pyt3 1:N:0:AAAA+N\n", b"@pyt3 1:N:0:AAAA+N\n")]
+    #[case::mixed_case_short(b"@pyt4 1:N:0:AAAA+AaCg\n", b"@pyt4 1:N:0:AAAA+cGtT\n")]
+    #[case::acgtn(b"@pyt5 1:N:0:AAAA+AcgTN\n", b"@pyt5 1:N:0:AAAA+NAcgT\n")]
+    #[case::all_as(b"@pyt6 1:N:0:AAAA+AAAA\n", b"@pyt6 1:N:0:AAAA+TTTT\n")]
+    #[case::all_cs(b"@pyt7 1:N:0:AAAA+CCCC\n", b"@pyt7 1:N:0:AAAA+GGGG\n")]
+    #[case::at_repeat(b"@pyt8 1:N:0:AAAA+ATATAT\n", b"@pyt8 1:N:0:AAAA+ATATAT\n")]
+    #[case::cg_repeat(b"@pyt9 1:N:0:AAAA+CGCGCG\n", b"@pyt9 1:N:0:AAAA+CGCGCG\n")]
+    #[case::ns_flanking(b"@pyt10 1:N:0:AAAA+NNACGTNN\n", b"@pyt10 1:N:0:AAAA+NNACGTNN\n")]
+    #[case::general_atcacg(b"@pyt11 1:N:0:AAAA+ATCACG\n", b"@pyt11 1:N:0:AAAA+CGTGAT\n")]
+    #[case::general_ttaggc(b"@pyt12 1:N:0:AAAA+TTAGGC\n", b"@pyt12 1:N:0:AAAA+GCCTAA\n")]
+    #[case::crlf(b"@r1 1:N:0:AAAA+ACG\r\n", b"@r1 1:N:0:AAAA+CGT\r\n")]
+    #[case::casava_1_7(
+        b"@HWUSI-EAS100R:6:73:941:1973#ACGT+ACGG/1\n",
+        b"@HWUSI-EAS100R:6:73:941:1973#ACGT+CCGT/1\n"
+    )]
+    #[case::casava_1_7_no_suffix(
+        b"@HW:6:73:941:1973#ACGT+ACGG\r\n",
+        b"@HW:6:73:941:1973#ACGT+CCGT\r\n"
+    )]
+    #[case::casava_1_7_single_index(b"@HW:6:73:941:1973#ACGG/2\n", b"@HW:6:73:941:1973#ACGG/2\n")]
+    #[case::casava_1_7_no_index(b"@HW:6:73:941:1973#0/1\n", b"@HW:6:73:941:1973#0/1\n")]
+    #[case::crlf_empty_i5(b"@r1 1:N:0:AAAA+\r\n", b"@r1 1:N:0:AAAA+\r\n")]
+    fn rewrite_header_i5_valid(
+        #[case] input: &[u8],
+        #[case] expected: &[u8],
+    ) -> std::io::Result<()> {
+        let mut header = input.to_vec();
+        rewrite_i5(&IndexLocation::default(), &mut header)?;
+        assert_eq!(
+            String::from_utf8_lossy(&header),
+            String::from_utf8_lossy(expected),
+            "\n\n     test input: {}\n    test output: {}\nexpected output: {}\n",
+            String::from_utf8_lossy(input),
+            String::from_utf8_lossy(&header),
+            String::from_utf8_lossy(expected),
+        );
+        // apply again to recover original input
+        rewrite_i5(&IndexLocation::default(), &mut header)?;
+        assert_eq!(
+            String::from_utf8_lossy(&header),
+            String::from_utf8_lossy(input),
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::casava_1_7(b"@HW:6:73:941:1973#ACGT+ACGG/1\n", "ACGT", "ACGG")]
+    #[case::casava_1_7_single_index(b"@HW:6:73:941:1973#ACGT/2\n", "ACGT", "")]
+    #[case::illumina(b"@r1 1:N:0:ACGT+ACGG\n", "ACGT", "ACGG")]
+    fn index_field_parts(
+        #[case] header: &[u8],
+        #[case] i7: &str,
+        #[case] i5: &str,
+    ) -> io::Result<()> {
+        let (i7_range, i5_range) = index_field(header)?;
+        assert_eq!(String::from_utf8_lossy(&header[i7_range]), i7);
+        assert_eq!(String::from_utf8_lossy(&header[i5_range]), i5);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::illumina(b"@r1 1:N:0:ACGT+ACGG\n", Some(HeaderFormat::Illumina))]
+    #[case::casava_1_7(b"@HW:6:73:941:1973#ACGT/1\n", Some(HeaderFormat::Illumina))]
+    #[case::mgi(b"@V350000000L1C001R0010000000/1 ACGT+ACGG\n", Some(HeaderFormat::Mgi))]
+    #[case::mgi_tab(
+        b"@E100000000L01C001R00100000001\tBC:Z:ACGT-ACGG\r\n",
+        Some(HeaderFormat::Mgi)
+    )]
+    #[case::ultima(
+        b"@014214_1-Z0001-0123456789\tBC:Z:ACGT-ACGG\n",
+        Some(HeaderFormat::Ultima)
+    )]
+    #[case::unknown(b"@r1 no index\n", None)]
+    fn detect_header_format(#[case] header: &[u8], #[case] expected: Option<HeaderFormat>) {
+        assert_eq!(HeaderFormat::detect(header), expected);
+    }
+
+    #[rstest]
+    #[case::mgi(
+        HeaderFormat::Mgi,
+        b"@V350000000L1C001R0010000000/1 x ACGT+ACGG\n",
+        b"@V350000000L1C001R0010000000/1 x ACGT+CCGT\n"
+    )]
+    #[case::mgi_tag(
+        HeaderFormat::Mgi,
+        b"@V350000000L1C001R0010000000/2\tBC:Z:ACGT-ACGG\r\n",
+        b"@V350000000L1C001R0010000000/2\tBC:Z:ACGT-CCGT\r\n"
+    )]
+    #[case::mgi_trailing_tag(
+        HeaderFormat::Mgi,
+        b"@V350000000L1C001R0010000000/1 BC:Z:AAAA+CCCC RG:Z:x\n",
+        b"@V350000000L1C001R0010000000/1 BC:Z:AAAA+GGGG RG:Z:x\n"
+    )]
+    #[case::mgi_trailing_field(
+        HeaderFormat::Mgi,
+        b"@V350000000L1C001R0010000000/1 ACGT+ACGG RG:Z:x\n",
+        b"@V350000000L1C001R0010000000/1 ACGT+CCGT RG:Z:x\n"
+    )]
+    #[case::ultima(
+        HeaderFormat::Ultima,
+        b"@014214_1-Z0001-0123456789\tBC:Z:ACGT-ACGG\tRG:Z:x\n",
+        b"@014214_1-Z0001-0123456789\tBC:Z:ACGT-CCGT\tRG:Z:x\n"
+    )]
+    fn rewrite_header_format(
+        #[case] format: HeaderFormat,
+        #[case] input: &[u8],
+        #[case] expected: &[u8],
+    ) -> io::Result<()> {
+        let mut header = input.to_vec();
+        rewrite_i5(&IndexLocation::Format(format), &mut header)?;
+        assert_eq!(
+            String::from_utf8_lossy(&header),
+            String::from_utf8_lossy(expected)
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::mgi_no_barcodes(
+        HeaderFormat::Mgi,
+        b"@V350000000L1C001R0010000000/1\n",
+        "missing barcodes"
+    )]
+    #[case::mgi_no_separator(
+        HeaderFormat::Mgi,
+        b"@V350000000L1C001R0010000000/1 RG:Z:x\n",
+        "missing barcodes"
+    )]
+    #[case::ultima_no_tag(
+        HeaderFormat::Ultima,
+        b"@014214_1-Z0001-0123456789\n",
+        "missing BC tag"
+    )]
+    fn header_format_invalid(
+        #[case] format: HeaderFormat,
+        #[case] input: &[u8],
+        #[case] msg_substr: &str,
+    ) {
+        let msg = format.index_field(input).unwrap_err().to_string();
+        assert!(msg.contains(msg_substr), "{msg}");
+    }
+
+    #[test]
+    fn detect_format_from_input() -> io::Result<()> {
+        let input = b"@V350000000L1C001R0010000000/1 ACGT+ACGG\nACGT\n+\n!!!!\n";
+        let mut reader = &input[..];
+        assert_eq!(detect_format(&mut reader)?, HeaderFormat::Mgi);
+        // the input is not consumed
+        assert_eq!(reader, &input[..]);
+        assert_eq!(
+            detect_format(&mut &b"@V350000000L1C001R0010000000"[..])?,
+            HeaderFormat::Mgi
+        );
+        assert_eq!(detect_format(&mut &b""[..])?, HeaderFormat::Illumina);
+        // an unrecognized first header is left to fail as a record in the default format
+        assert_eq!(
+            detect_format(&mut &b"@r1 1:N:0:AAAAACGT\nACGT\n+\n!!!!\n"[..])?,
+            HeaderFormat::Illumina
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::field(
+        IndexLocation::Field(1),
+        b"@r1 1:N:0:AAAA+ACGG BX:Z:ACGT-1\n",
+        b"@r1 1:N:0:AAAA+CCGT BX:Z:ACGT-1\n"
+    )]
+    #[case::field_without_colon(
+        IndexLocation::Field(3),
+        b"@r1\tx\t\tAAAA+ACGG\tyy\r\n",
+        b"@r1\tx\t\tAAAA+CCGT\tyy\r\n"
+    )]
+    #[case::tag(
+        IndexLocation::Tag("BC".to_string()),
+        b"@r1 1:N:0:1 BCX:Z:A-C BC:Z:AAAA-ACGG RX:Z:TT\n",
+        b"@r1 1:N:0:1 BCX:Z:A-C BC:Z:AAAA-CCGT RX:Z:TT\n"
+    )]
+    #[case::regex(
+        IndexLocation::Regex(Regex::new(r"#(?<i7>[ACGTN]+)_(?<i5>[ACGTN]+)").unwrap()),
+        b"@r1#AAAA_ACGG/1 extra\n",
+        b"@r1#AAAA_CCGT/1 extra\n"
+    )]
+    fn rewrite_index_location(
+        #[case] location: IndexLocation,
+        #[case] input: &[u8],
+        #[case] expected: &[u8],
+    ) -> io::Result<()> {
+        let mut header = input.to_vec();
+        rewrite_i5(&location, &mut header)?;
+        assert_eq!(
+            String::from_utf8_lossy(&header),
+            String::from_utf8_lossy(expected)
+        );
+        let (i7, _) = location.index_field(&header)?;
+        assert_eq!(&header[i7], b"AAAA");
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::missing_field(
+        IndexLocation::Field(2),
+        b"@r1 1:N:0:AAAA+ACGT\n",
+        "missing comment field 2"
+    )]
+    #[case::missing_tag(IndexLocation::Tag("BC".to_string()), b"@r1 BCX:Z:AAAA+ACGT\n", "missing BC tag")]
+    #[case::tag_read_name(IndexLocation::Tag("r1".to_string()), b"@r1:A+C\n", "missing r1 tag")]
+    #[case::no_separator(IndexLocation::Field(1), b"@r1 1:N:0:AAAA x\n", "'+'")]
+    #[case::empty_field(IndexLocation::Field(2), b"@r1 x  AAAA+ACGT\n", "'+'")]
+    #[case::no_match(
+        IndexLocation::Regex(Regex::new("#(?<i5>[ACGT]+)").unwrap()),
+        b"@r1 1:N:0:AAAA+ACGT\n",
+        "does not match"
+    )]
+    fn index_location_invalid(
+        #[case] location: IndexLocation,
+        #[case] input: &[u8],
+        #[case] msg_substr: &str,
+    ) {
+        let msg = location.index_field(input).unwrap_err().to_string();
+        assert!(msg.contains(msg_substr), "{msg}");
+    }
+
+    #[rstest]
+    #[case::empty_header(b"\n", "'@'")]
+    #[case::no_colon_short(b"@\n", "':'")]
+    #[case::no_colon_long(b"@r6 no_index_here\n", "':'")]
+    #[case::no_plus(b"@r5 1:N:0:AAAA\n", "'+'")]
+    #[case::no_newline(b"@r7 1:N:0:CCCC+AGTC", "newline")]
+    fn rewrite_header_i5_invalid(#[case] input: &[u8], #[case] msg_substr: &str) {
+        let mut header = input.to_vec();
+        let err = rewrite_i5(&IndexLocation::default(), &mut header)
+            .expect_err("expected rewrite_i5 to fail");
+        let msg = err.to_string();
+        assert!(
+            msg.contains(msg_substr),
+            "error message did not contain expected substring.\n  expected: {msg_substr}\n  got: {msg}"
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use crate::chunk::ChunkReader;
+use crate::compression;
+use crate::fastq::Position;
+use crate::files::with_name;
+use crate::fix::{record_error, Fix};
+use crate::policy::ErrorHandler;
+use crate::report::Stats;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Number of patches that are written (and synced to disk) together.
+const BATCH_PATCHES: usize = 1 << 16;
+
+/// First line of a journal file.
+const JOURNAL_MAGIC: &str = "fastq-fix-i5 in-place journal v1";
+
+/// A change of the bytes at `offset` of a file.
+#[derive(Debug, PartialEq, Eq)]
+struct Patch {
+    offset: u64,
+    original: Vec<u8>,
+    patched: Vec<u8>,
+}
+
+impl Patch {
+    /// The patch for the bytes that differ between `original` and `patched`,
+    /// which start at `offset` of the file. Returns None if nothing changed.
+    fn diff(offset: u64, original: &[u8], patched: &[u8]) -> Option<Patch> {
+        let differs = |(a, b): (&u8, &u8)| a != b;
+        let first = original.iter().zip(patched).position(differs)?;
+        let last = original.iter().zip(patched).rposition(differs)?;
+        Some(Patch {
+            offset: offset + first as u64,
+            original: original[first..=last].to_vec(),
+            patched: patched[first..=last].to_vec(),
+        })
+    }
+}
+
+/// Write `bytes` at each offset of the file.
+fn write_at<'a>(file: &mut File, patches: impl Iterator<Item = (u64, &'a [u8])>) -> io::Result<()> {
+    for (offset, bytes) in patches {
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(bytes)?;
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Progress of an in-place run, stored next to the file so that an interrupted run can be
+/// resumed or undone. Patches are first recorded as pending with their original bytes, then
+/// written to the file, and once the file is synced they are committed. After a crash the
+/// pending patches may or may not have been written, so their original bytes are restored.
+#[derive(Debug, PartialEq, Eq)]
+struct Journal {
+    /// size of the file, which patching never changes
+    size: u64,
+    /// offset at which the run stops: the end of the file, or where an undone run stopped
+    end: u64,
+    /// true if the run undoes an interrupted run
+    undo: bool,
+    /// position of the first record that has not been patched
+    committed: Position,
+    /// offsets and original bytes of the patches that are being written
+    pending: Vec<(u64, Vec<u8>)>,
+}
+
+impl Journal {
+    fn new(size: u64) -> Self {
+        Journal {
+            size,
+            end: size,
+            undo: false,
+            committed: Position::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Path of the journal for the file at `path`.
+    fn path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".fix-i5-journal");
+        PathBuf::from(name)
+    }
+
+    fn to_text(&self) -> String {
+        let Position {
+            record,
+            line,
+            offset,
+        } = self.committed;
+        let mut text = format!(
+            "{JOURNAL_MAGIC}\nsize {}\nend {}\nundo {}\ncommitted {offset} {record} {line}\n",
+            self.size,
+            self.end,
+            u8::from(self.undo)
+        );
+        for (offset, original) in &self.pending {
+            text += &format!("pending {offset} {}\n", to_hex(original));
+        }
+        text
+    }
+
+    fn parse(text: &str) -> io::Result<Journal> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid in-place journal");
+        let mut lines = text.lines();
+        if lines.next() != Some(JOURNAL_MAGIC) {
+            return Err(invalid());
+        }
+        let mut journal = Journal::new(0);
+        for line in lines {
+            let mut fields = line.split(' ');
+            let key = fields.next();
+            let values: Vec<&str> = fields.collect();
+            let number = |i: usize| -> io::Result<u64> {
+                values
+                    .get(i)
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(invalid)
+            };
+            match key {
+                Some("size") => journal.size = number(0)?,
+                Some("end") => journal.end = number(0)?,
+                Some("undo") => journal.undo = number(0)? == 1,
+                Some("committed") => {
+                    journal.committed = Position {
+                        offset: number(0)?,
+                        record: number(1)?,
+                        line: number(2)?,
+                    }
+                }
+                Some("pending") => journal.pending.push((
+                    number(0)?,
+                    values
+                        .get(1)
+                        .and_then(|v| from_hex(v))
+                        .ok_or_else(invalid)?,
+                )),
+                _ => return Err(invalid()),
+            }
+        }
+        if journal.end > journal.size || journal.committed.offset > journal.end {
+            return Err(invalid());
+        }
+        Ok(journal)
+    }
+
+    /// Read the journal at `path`, or None if there is none.
+    fn read(path: &Path) -> io::Result<Option<Journal>> {
+        match fs::read_to_string(path) {
+            Ok(text) => Journal::parse(&text).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the journal at `path` atomically, and sync it to disk.
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_name = path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        let mut file = File::create(&tmp)?;
+        file.write_all(self.to_text().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Rewrite the records of an uncompressed FASTQ file in place, overwriting only the bytes
+/// that change (the i5 in the header). Progress is recorded in a journal next to the file,
+/// so that an interrupted run is resumed by running it again, or undone if `undo` is set.
+/// Invalid records are passed to `errors` and left unchanged in the file.
+/// Returns the number of records processed in this run.
+pub fn fix_in_place(
+    path: &Path,
+    fix: Fix,
+    undo: bool,
+    errors: &mut ErrorHandler,
+    stats: Option<&mut Stats>,
+) -> io::Result<u64> {
+    let name = path.display().to_string();
+    let label = |e| with_name(e, &name);
+    let mut reader = File::open(path).map_err(label)?;
+    let metadata = reader.metadata().map_err(label)?;
+    let size = metadata.len();
+    if !metadata.is_file() {
+        return Err(label(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--in-place requires a regular file",
+        )));
+    }
+    if compression::is_compressed(&mut reader).map_err(label)? {
+        return Err(label(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot patch a compressed file in place",
+        )));
+    }
+    let mut writer = OpenOptions::new().write(true).open(path).map_err(label)?;
+
+    let journal_path = Journal::path(path);
+    let journal_name = journal_path.display().to_string();
+    let label_journal = |e| with_name(e, &journal_name);
+    let mut journal = match Journal::read(&journal_path).map_err(label_journal)? {
+        Some(mut journal) => {
+            if journal.size != size {
+                return Err(label_journal(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{name} has changed size since the interrupted run"),
+                )));
+            }
+            let pending = journal.pending.iter().map(|(o, b)| (*o, b.as_slice()));
+            write_at(&mut writer, pending).map_err(label)?;
+            writer.sync_data().map_err(label)?;
+            journal.pending.clear();
+            if undo && !journal.undo {
+                // patching again restores the records that were patched
+                journal.end = journal.committed.offset;
+                journal.committed = Position::new();
+                journal.undo = true;
+                eprintln!(
+                    "{name}: undoing the interrupted run up to byte offset {}",
+                    journal.end
+                );
+            } else {
+                let run = if journal.undo { "undo" } else { "run" };
+                eprintln!(
+                    "{name}: resuming the interrupted {run} at {}",
+                    journal.committed
+                );
+            }
+            journal.write(&journal_path).map_err(label_journal)?;
+            journal
+        }
+        None if undo => {
+            return Err(label(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no interrupted run to undo ({journal_name} not found)"),
+            )))
+        }
+        None => {
+            let journal = Journal::new(size);
+            journal.write(&journal_path).map_err(label_journal)?;
+            journal
+        }
+    };
+
+    let start = journal.committed.clone();
+    reader.seek(SeekFrom::Start(start.offset)).map_err(label)?;
+    let mut records = ChunkReader::resume(reader.take(journal.end - start.offset), start.clone());
+    let result = patch_records(
+        &mut records,
+        &mut writer,
+        &mut journal,
+        &journal_path,
+        fix,
+        errors,
+        stats,
+        &name,
+    );
+    match result {
+        Ok(()) => fs::remove_file(&journal_path).map_err(label_journal)?,
+        // nothing was patched, so there is nothing to resume or undo
+        Err(_) if !journal.undo && journal.committed.offset == 0 => {
+            fs::remove_file(&journal_path).map_err(label_journal)?
+        }
+        Err(_) => eprintln!(
+            "{name}: patched up to {}, run again with --undo to restore the original file",
+            journal.committed
+        ),
+    }
+    result?;
+    Ok(records.position().record - start.record)
+}
+
+/// Patch the records in batches, committing each batch to the journal once it is written.
+#[allow(clippy::too_many_arguments)]
+fn patch_records<R: Read>(
+    records: &mut ChunkReader<R>,
+    writer: &mut File,
+    journal: &mut Journal,
+    journal_path: &Path,
+    fix: Fix,
+    errors: &mut ErrorHandler,
+    mut stats: Option<&mut Stats>,
+    name: &str,
+) -> io::Result<()> {
+    let label = |e| with_name(e, name);
+    let label_journal = |e| with_name(e, &journal_path.display().to_string());
+    let mut batch: Vec<Patch> = Vec::new();
+    loop {
+        let more = records
+            .next_record(&mut io::sink())
+            .map_err(|e| record_error(name, records.position(), e))?;
+        if batch.len() >= BATCH_PATCHES || !more {
+            journal.pending = batch
+                .iter()
+                .map(|p| (p.offset, p.original.clone()))
+                .collect();
+            journal.write(journal_path).map_err(label_journal)?;
+            write_at(
+                writer,
+                batch.iter().map(|p| (p.offset, p.patched.as_slice())),
+            )
+            .map_err(label)?;
+            writer.sync_data().map_err(label)?;
+            batch.clear();
+            journal.pending.clear();
+            journal.committed = records.position().clone();
+            journal.write(journal_path).map_err(label_journal)?;
+        }
+        if !more {
+            return Ok(());
+        }
+
+        let position = records.position().clone();
+        let mut record = records.record_mut();
+        let original = record.as_bytes().to_vec();
+        match fix.apply(&mut record) {
+            Ok(()) => {
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats
+                        .add(record.header(), fix.location)
+                        .map_err(|e| record_error(name, &position, e))?;
+                }
+                // The header and a separator line that repeats its title
+                let (header, rest) = original.split_at(record.header().len());
+                batch.extend(Patch::diff(position.offset, header, record.header()));
+                batch.extend(Patch::diff(
+                    position.offset + header.len() as u64,
+                    rest,
+                    &record.as_bytes()[header.len()..],
+                ));
+            }
+            Err(e) => errors.handle(
+                record_error(name, &position, e),
+                position.record,
+                &[records.record()],
+                &mut [io::sink()],
+            )?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patch_diff() {
+        assert_eq!(
+            Patch::diff(10, b"@r1 1:N:0:AAAA+ACGGT\n", b"@r1 1:N:0:AAAA+ACCGT\n"),
+            Some(Patch {
+                offset: 10 + 17,
+                original: b"G".to_vec(),
+                patched: b"C".to_vec(),
+            })
+        );
+        assert_eq!(Patch::diff(0, b"@r1:AAAA+ACGT\n", b"@r1:AAAA+ACGT\n"), None);
+    }
+
+    #[test]
+    fn journal_roundtrip() -> io::Result<()> {
+        let journal = Journal {
+            size: 1000,
+            end: 900,
+            undo: true,
+            committed: Position {
+                record: 3,
+                line: 9,
+                offset: 100,
+            },
+            pending: vec![(120, b"ACGT".to_vec()), (200, b"\n\xff".to_vec())],
+        };
+        assert_eq!(Journal::parse(&journal.to_text())?, journal);
+        assert!(Journal::parse("fastq-fix-i5 in-place journal v1\nsize 10\nend 20\n").is_err());
+        assert!(Journal::parse("pending 1 4\n").is_err());
+        Ok(())
+    }
+}
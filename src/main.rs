@@ -1,229 +1,740 @@
-use clap::Parser;
-use memchr::{memchr, memrchr};
-use std::io::{self, BufRead, Read, Write};
+mod bam;
+mod bgzf;
+mod check;
+mod chunk;
+mod compression;
+mod demux;
+mod dna;
+mod fastq;
+mod files;
+mod fix;
+mod header;
+mod inplace;
+mod policy;
+mod report;
+mod sam;
+mod samplesheet;
+mod tags;
+
+use clap::{CommandFactory, Parser, Subcommand};
+use compression::Compression;
+use fastq::{LineEndings, ReadOptions, Wrapping};
+use files::{Format, Input, Output};
+use fix::{fix_i5, Fix};
+use header::{HeaderFormat, IndexLocation};
+use policy::{ErrorHandler, OnError};
+use regex::bytes::Regex;
+use report::Stats;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 #[derive(Parser)]
 #[command(
     name = "fastq-i5-rc",
     version,
     about = "Rewrites FASTQ headers by reverse-complementing the i5 (Index2 / P5) barcode",
-    long_about = "A fast, streaming tool to rewrite FASTQ headers by reverse-complementing the i5 (Index2 / P5) barcode, without modifying read sequences or quality scores. Headers are expected to end with the standard Illumina `:<i7>+<i5>` format."
+    long_about = "A fast, streaming tool to rewrite FASTQ headers by reverse-complementing the i5 (Index2 / P5) barcode, without modifying read sequences or quality scores. Headers are expected to end with the standard Illumina `:<i7>+<i5>` format.",
+    args_conflicts_with_subcommands = true
 )]
-struct Args {}
-
-/// Return the complement of a DNA base (A,C,G,T,N), preserving case.
-#[inline(always)]
-const fn complement_base(b: u8) -> u8 {
-    // Handles A,C,G,T,N (upper/lower). Leaves other bytes unchanged.
-    match b {
-        b'A' => b'T',
-        b'C' => b'G',
-        b'G' => b'C',
-        b'T' => b'A',
-        b'N' => b'N',
-        b'a' => b't',
-        b'c' => b'g',
-        b'g' => b'c',
-        b't' => b'a',
-        b'n' => b'n',
-        _ => b,
-    }
-}
-
-/// Reverse-complement a DNA sequence in-place.
-#[inline(always)]
-fn reverse_complement_in_place(buf: &mut [u8]) {
-    let mut i = 0;
-    let mut j = buf.len();
-    while i < j {
-        j -= 1;
-        let a = complement_base(buf[i]);
-        let b = complement_base(buf[j]);
-        buf[i] = b;
-        buf[j] = a;
-        i += 1;
-    }
-}
-
-/// Reverse-complement the i5 part of a FASTQ header line in-place
-/// Header is expected to start with '@' and end with ":i7+i5\n".
-/// Returns an error if the header is invalid
-fn rewrite_header_i5(header: &mut [u8]) -> io::Result<()> {
-    if header.is_empty() || header[0] != b'@' {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid FASTQ header: does not start with '@'",
-        ));
-    }
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    if header.last() != Some(&b'\n') {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid FASTQ header: missing trailing newline",
-        ));
-    }
+    #[command(flatten)]
+    args: Args,
+}
 
-    // Find last ':' in the header.
-    let Some(colon_index) = memrchr(b':', header) else {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid FASTQ header: missing ':' before index field",
-        ));
-    };
-    let after_colon_index = colon_index + 1;
+#[derive(Subcommand)]
+enum Command {
+    /// Demultiplex FASTQ records into one file per sample using a sample sheet of i7/i5 pairs
+    Demux(DemuxArgs),
+    /// Reverse-complement the index2 (i5) column of an Illumina sample sheet (v1 or v2)
+    /// or AVITI run manifest, or convert between the two
+    Samplesheet(SampleSheetArgs),
+    /// Validate FASTQ input without writing any output, reporting every problem found
+    Check(CheckArgs),
+}
 
-    // Find '+' after that last ':'.
-    let Some(relative_plus_index) = memchr(b'+', &header[after_colon_index..]) else {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid FASTQ header: missing '+' in index field",
-        ));
-    };
-    let after_plus_index = after_colon_index + relative_plus_index + 1;
+#[derive(clap::Args)]
+struct Args {
+    /// Input FASTQ file(s), concatenated in the given order, or a single SAM or (unaligned) BAM file
+    /// ('-' for stdin)
+    #[arg(default_value = files::STDIO)]
+    inputs: Vec<PathBuf>,
 
-    // i5 header is everything after '+' excluding the final newline character
-    let stop_h5_index = header.len() - 1; // exclude final '\n'
-    let i5 = &mut header[after_plus_index..stop_h5_index];
-    reverse_complement_in_place(i5);
-    Ok(())
+    /// Output FASTQ (or SAM or BAM) file ('-' for stdout), repeat once per input in paired mode
+    #[arg(short, long, default_value = files::STDIO)]
+    output: Vec<PathBuf>,
+
+    /// Process 2 (R1, R2) or 4 (e.g. R1, R2, I1, I2) input files of the same run in lockstep,
+    /// checking that the read names of all mates agree
+    #[arg(short, long)]
+    paired: bool,
+
+    /// Read the records of each input in pairs of interleaved mates (R1, R2, R1, R2, ...),
+    /// checking that the read names and i5 of the mates agree
+    #[arg(long, conflicts_with_all = ["paired", "index_read"])]
+    interleaved: bool,
+
+    /// Also reverse-complement the sequence and reverse the quality of each record,
+    /// for an index read (I2) FASTQ. In paired mode this requires 4 input files (R1, R2, I1, I2)
+    /// and applies only to the last one (I2)
+    #[arg(short, long)]
+    index_read: bool,
+
+    /// Leave the headers unchanged (only useful with --index-read)
+    #[arg(long, requires = "index_read")]
+    skip_header: bool,
+
+    /// Also validate the separator, sequence and quality lines of each record
+    #[arg(long)]
+    strict: bool,
+
+    /// Accept records whose sequence and quality are wrapped over several lines
+    /// (written as they are, unless with --unwrap)
+    #[arg(long)]
+    wrapped: bool,
+
+    /// Join the wrapped sequence and quality lines of each record into a single line each
+    #[arg(long, requires = "wrapped")]
+    unwrap: bool,
+
+    /// Line endings of the output records: those of the input (LF or CRLF), or LF only
+    #[arg(long, value_enum, default_value_t = LineEndings::Keep)]
+    line_endings: LineEndings,
+
+    /// Write a JSON report with the number of records and the index pairs seen to this file
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Sample sheet of the expected i7/i5 pairs (in the rewritten orientation, see demux),
+    /// all other index pairs are listed as unknown in the report
+    #[arg(long, requires = "report")]
+    report_sample_sheet: Option<PathBuf>,
+
+    /// Number of most frequent unknown index pairs to list in the report
+    #[arg(long, default_value_t = 20)]
+    report_top: usize,
+
+    /// What to do with a record whose header cannot be rewritten (or that is invalid with --strict)
+    #[arg(long, value_enum, default_value_t = OnError::Fail)]
+    on_error: OnError,
+
+    /// FASTQ file for the quarantined records (all mates in paired mode), required for --on-error quarantine
+    #[arg(long, required_if_eq("on_error", "quarantine"))]
+    reject_file: Option<PathBuf>,
+
+    /// Give up if there are more than this number of invalid records
+    #[arg(long)]
+    max_errors: Option<u64>,
+
+    /// Give up if more than this fraction (0-1) of the records are invalid
+    /// (checked from the 1000th record on, and at the end of the run)
+    #[arg(long)]
+    max_error_fraction: Option<f64>,
+
+    /// Patch the i5 in the headers of the (uncompressed) input files in place instead of writing
+    /// an output. An interrupted run is resumed by running it again
+    #[arg(long, conflicts_with_all = ["output", "paired", "interleaved", "index_read", "skip_header", "wrapped", "threads"])]
+    in_place: bool,
+
+    /// Restore the original input files of an interrupted --in-place run
+    #[arg(long, requires = "in_place")]
+    undo: bool,
+
+    /// Number of worker threads rewriting records (the output is the same for any number)
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: u32,
+
+    #[command(flatten)]
+    header: HeaderArgs,
+
+    #[command(flatten)]
+    compression: CompressionArgs,
 }
 
-/// Read one line (including the trailing '\n' if present) into line, erasing previous contents.
-/// Returns number of bytes read (0 on EOF).
-#[inline(always)]
-fn read_line<R: Read>(reader: &mut io::BufReader<R>, line: &mut Vec<u8>) -> io::Result<usize> {
-    line.clear();
-    let mut total = 0usize;
-    loop {
-        let available = reader.fill_buf()?;
-        if available.is_empty() {
-            return Ok(total);
+impl Args {
+    /// The fix of the records given by these arguments, with the index at `location`.
+    fn fix<'a>(&self, location: &'a IndexLocation) -> Fix<'a> {
+        Fix {
+            header: !self.skip_header,
+            index_read: self.index_read,
+            strict: self.strict,
+            location,
         }
-        if let Some(pos) = memchr(b'\n', available) {
-            // include newline
-            line.extend_from_slice(&available[..=pos]);
-            let consume = pos + 1;
-            reader.consume(consume);
-            total += consume;
-            return Ok(total);
+    }
+}
+
+#[derive(clap::Args)]
+struct DemuxArgs {
+    /// Input FASTQ file(s), concatenated in the given order ('-' for stdin)
+    #[arg(default_value = files::STDIO)]
+    inputs: Vec<PathBuf>,
+
+    /// Sample sheet with sample name, i7 and i5 columns (comma or tab separated, with a header line)
+    #[arg(short, long)]
+    sample_sheet: PathBuf,
+
+    /// Directory for the output FASTQ files: one per sample plus one for undetermined records
+    #[arg(short = 'd', long, default_value = ".")]
+    outdir: PathBuf,
+
+    /// Reverse-complement the i5 in the headers before matching them to the sample sheet
+    #[arg(short, long)]
+    rc_i5: bool,
+
+    /// Maximum number of mismatches allowed in the i7 index
+    #[arg(long, default_value_t = 1)]
+    i7_mismatches: usize,
+
+    /// Maximum number of mismatches allowed in the i5 index
+    #[arg(long, default_value_t = 1)]
+    i5_mismatches: usize,
+
+    #[command(flatten)]
+    header: HeaderArgs,
+
+    #[command(flatten)]
+    compression: CompressionArgs,
+}
+
+#[derive(clap::Args)]
+struct SampleSheetArgs {
+    /// Input Illumina SampleSheet.csv or AVITI RunManifest.csv ('-' for stdin)
+    #[arg(default_value = files::STDIO)]
+    input: PathBuf,
+
+    /// Output sample sheet ('-' for stdout)
+    #[arg(short, long, default_value = files::STDIO)]
+    output: PathBuf,
+
+    /// Convert the samples to a new sample sheet in this format
+    /// (by default the input is written back with only index2 changed)
+    #[arg(long, value_enum)]
+    to: Option<samplesheet::Format>,
+
+    /// Copy index2 unchanged when converting instead of reverse-complementing it
+    #[arg(long, requires = "to")]
+    keep_index2: bool,
+}
+
+#[derive(clap::Args)]
+struct CheckArgs {
+    /// Input FASTQ file(s) ('-' for stdin)
+    #[arg(default_value = files::STDIO)]
+    inputs: Vec<PathBuf>,
+
+    /// Also validate the separator, sequence and quality lines of each record
+    #[arg(long)]
+    strict: bool,
+
+    /// Accept records whose sequence and quality are wrapped over several lines
+    #[arg(long)]
+    wrapped: bool,
+
+    #[command(flatten)]
+    header: HeaderArgs,
+}
+
+#[derive(clap::Args)]
+struct HeaderArgs {
+    /// Sequencer vendor format of the headers [default: detected from the first record of each input]
+    #[arg(long, value_enum, conflicts_with_all = ["index_field", "index_tag", "index_regex"])]
+    header_format: Option<HeaderFormat>,
+
+    /// Find the index in this whitespace-separated comment field of the headers
+    /// (1 for the first field after the read name) after its last ':',
+    /// instead of after the last ':' of the header
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..),
+          conflicts_with_all = ["index_tag", "index_regex"])]
+    index_field: Option<u32>,
+
+    /// Find the index in the comment field of the headers starting with this tag, after its last ':'
+    /// (e.g. BC for `BC:Z:<i7>+<i5>`)
+    #[arg(long, value_name = "TAG", value_parser = parse_index_tag, conflicts_with = "index_regex")]
+    index_tag: Option<String>,
+
+    /// Find the index in the headers with this regular expression, with named groups
+    /// `i5` and (optionally) `i7`, e.g. '#(?<i7>[ACGTN]+)\+(?<i5>[ACGTN]+)'
+    #[arg(long, value_name = "REGEX", value_parser = parse_index_regex)]
+    index_regex: Option<Regex>,
+}
+
+impl HeaderArgs {
+    /// The location of the index in the headers of input, detecting its header format
+    /// from the first record if neither the format nor the location are given.
+    fn location(&self, input: &mut Input) -> io::Result<IndexLocation> {
+        Ok(if let Some(n) = self.index_field {
+            IndexLocation::Field(n as usize)
+        } else if let Some(tag) = &self.index_tag {
+            IndexLocation::Tag(tag.clone())
+        } else if let Some(regex) = &self.index_regex {
+            IndexLocation::Regex(regex.clone())
+        } else if let Some(format) = self.header_format {
+            IndexLocation::Format(format)
         } else {
-            // consume all
-            line.extend_from_slice(available);
-            let consume = available.len();
-            reader.consume(consume);
-            total += consume;
-        }
+            IndexLocation::Format(header::detect_format(input).map_err(|e| input.error(e))?)
+        })
+    }
+}
+
+fn parse_index_tag(tag: &str) -> Result<String, String> {
+    if tag.is_empty() || tag.contains(|c: char| c == ':' || c.is_whitespace()) {
+        return Err("a tag cannot be empty or contain ':' or whitespace".to_string());
+    }
+    Ok(tag.to_string())
+}
+
+fn parse_index_regex(regex: &str) -> Result<Regex, String> {
+    let regex = Regex::new(regex).map_err(|e| e.to_string())?;
+    if !regex.capture_names().any(|name| name == Some("i5")) {
+        return Err("the regex has no named group 'i5', e.g. (?<i5>[ACGTN]+)".to_string());
     }
+    Ok(regex)
+}
+
+#[derive(clap::Args)]
+struct CompressionArgs {
+    /// Compress the output using this format [default: inferred from the output file extension, otherwise none]
+    #[arg(short = 'z', long, value_enum)]
+    compress: Option<Compression>,
+
+    /// Compression level for the output (0-9 for gzip and BGZF, 0-22 for zstd)
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=compression::MAX_ZSTD_LEVEL as i64))]
+    compression_level: u32,
+
+    /// Use zstd long distance matching with a window of 2^WINDOW_LOG bytes for zstd output
+    #[arg(long, value_name = "WINDOW_LOG", num_args = 0..=1, require_equals = true, default_missing_value = "27",
+          value_parser = clap::value_parser!(u32).range(10..=31))]
+    zstd_long: Option<u32>,
+
+    /// Number of threads compressing BGZF or zstd output
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    compression_threads: u32,
+
+    /// Also write a .gzi index next to each BGZF compressed output file (for random access)
+    #[arg(long)]
+    gzi: bool,
 }
 
-/// Read FASTQ records from stdin, rewrite headers by reverse-complementing the i5 barcodes,
-/// and write modified records to stdout.
-fn main() -> io::Result<()> {
-    let _args = Args::parse();
-    const IO_BUFFER_BYTES: usize = 64 * 1024; // 64 kB buffer for I/O
-    let stdin = io::stdin();
-    let mut input = io::BufReader::with_capacity(IO_BUFFER_BYTES, stdin.lock());
-    let stdout = io::stdout();
-    let mut output = io::BufWriter::with_capacity(IO_BUFFER_BYTES, stdout.lock());
+impl CompressionArgs {
+    fn create(&self, path: &Path) -> io::Result<Output> {
+        self.create_as(path, self.compress)
+    }
 
-    // Buffer for a FASTQ record line (a record is 4 lines where the first line is the header)
-    let mut line = Vec::<u8>::with_capacity(1024);
-    const N_LINES_PER_RECORD: usize = 4;
+    /// Create an output with the given compression instead of the one of these arguments.
+    fn create_as(&self, path: &Path, compression: Option<Compression>) -> io::Result<Output> {
+        let options = compression::Options {
+            level: self.compression_level,
+            threads: self.compression_threads as usize,
+            index: self.gzi,
+            zstd_window_log: self.zstd_long,
+        };
+        Output::create(path, compression, &options)
+    }
+}
 
-    loop {
-        // rewrite header line
-        if read_line(&mut input, &mut line)? == 0 {
-            break; // no header: EOF
+/// Read FASTQ records from the input files (or stdin), rewrite headers by reverse-complementing
+/// the i5 barcodes, and write modified records to the output file (or stdout).
+/// Compressed input is decompressed transparently.
+fn run(args: Args) -> io::Result<()> {
+    for output in args
+        .output
+        .iter()
+        .chain(&args.reject_file)
+        .chain(&args.report)
+    {
+        files::check_not_input(output, &args.inputs)?;
+    }
+    let read_options = ReadOptions {
+        wrapping: match (args.wrapped, args.unwrap) {
+            (false, _) => Wrapping::None,
+            (true, false) => Wrapping::Keep,
+            (true, true) => Wrapping::Unwrap,
+        },
+        line_endings: args.line_endings,
+    };
+    let samples = match &args.report_sample_sheet {
+        Some(path) => demux::read_sample_sheet(path)?,
+        None => Vec::new(),
+    };
+    let mut stats = args.report.as_ref().map(|_| Stats::default());
+    let rejects = match &args.reject_file {
+        Some(path) => Some(args.compression.create(path)?),
+        None => None,
+    };
+    let mut errors = ErrorHandler::new(
+        args.on_error,
+        rejects,
+        args.max_errors,
+        args.max_error_fraction,
+    );
+    let records;
+    if args.in_place {
+        let mut total = 0;
+        for path in &args.inputs {
+            let location = args.header.location(&mut Input::open(path)?)?;
+            let fix = args.fix(&location);
+            let records = inplace::fix_in_place(path, fix, args.undo, &mut errors, stats.as_mut())?;
+            errors.end_input(records);
+            total += records;
         }
-        rewrite_header_i5(&mut line)?;
-        output.write_all(&line)?;
-
-        // copy remaining lines of the FASTQ record unchanged
-        for _ in 1..N_LINES_PER_RECORD {
-            if read_line(&mut input, &mut line)? == 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "truncated FASTQ record (expected 4 lines)",
-                ));
+        records = total;
+    } else if args.paired {
+        let mut inputs = args
+            .inputs
+            .iter()
+            .map(|path| Input::open(path))
+            .collect::<io::Result<Vec<_>>>()?;
+        let location = args.header.location(&mut inputs[0])?;
+        let fix = args.fix(&location);
+        let mut outputs = args
+            .output
+            .iter()
+            .map(|path| args.compression.create(path))
+            .collect::<io::Result<Vec<_>>>()?;
+        // only the last input (I2) is an index read
+        let mut fixes = vec![
+            Fix {
+                index_read: false,
+                ..fix
+            };
+            inputs.len()
+        ];
+        fixes[inputs.len() - 1] = fix;
+        records = fix_i5(
+            &mut inputs,
+            &mut outputs,
+            &fixes,
+            &mut errors,
+            stats.as_mut(),
+            args.threads as usize,
+            read_options,
+        )?;
+        outputs.into_iter().try_for_each(Output::finish)?;
+    } else {
+        let first = Input::open_any(&args.inputs[0])?;
+        if first.format() != Format::Fastq {
+            records = run_alignments(&args, first, &mut errors, stats.as_mut())?;
+        } else {
+            let mut output = args.compression.create(&args.output[0])?;
+            let mut first = Some(first);
+            let mut total = 0;
+            for path in &args.inputs {
+                let mut input = match first.take() {
+                    Some(input) => input,
+                    None => Input::open(path)?,
+                };
+                let location = args.header.location(&mut input)?;
+                let records = if args.interleaved {
+                    fix::fix_interleaved(
+                        &mut input,
+                        &mut output,
+                        args.fix(&location),
+                        &mut errors,
+                        stats.as_mut(),
+                        args.threads as usize,
+                        read_options,
+                    )?
+                } else {
+                    fix_i5(
+                        std::slice::from_mut(&mut input),
+                        std::slice::from_mut(&mut output),
+                        &[args.fix(&location)],
+                        &mut errors,
+                        stats.as_mut(),
+                        args.threads as usize,
+                        read_options,
+                    )?
+                };
+                errors.end_input(records);
+                total += records;
             }
-            output.write_all(&line)?;
+            output.finish()?;
+            records = total;
         }
     }
+    if let Some(stats) = stats.as_mut() {
+        stats.set_totals(records, errors.errors());
+    }
+    errors.finish(records)?;
+    if let (Some(path), Some(stats)) = (&args.report, stats) {
+        let report = stats.to_json(!args.skip_header, &samples, args.report_top);
+        write_json(path, &report)?;
+    }
+    Ok(())
+}
+
+/// Rewrite the barcode tags of a SAM or BAM input, writing an output in the same format
+/// (BAM output is always BGZF compressed).
+/// Returns an error for the options that only apply to FASTQ input.
+fn run_alignments(
+    args: &Args,
+    mut input: Input,
+    errors: &mut ErrorHandler,
+    stats: Option<&mut Stats>,
+) -> io::Result<u64> {
+    let format = input.format();
+    let fastq_options = [
+        (args.index_read, "--index-read"),
+        (args.interleaved, "--interleaved"),
+        (args.strict, "--strict"),
+        (args.wrapped, "--wrapped"),
+        (args.line_endings != LineEndings::Keep, "--line-endings"),
+        (args.threads > 1, "--threads"),
+        (args.header.header_format.is_some(), "--header-format"),
+        (args.header.index_field.is_some(), "--index-field"),
+        (args.header.index_tag.is_some(), "--index-tag"),
+        (args.header.index_regex.is_some(), "--index-regex"),
+        (
+            args.on_error == OnError::Quarantine,
+            "--on-error quarantine",
+        ),
+    ];
+    let unsupported = if args.inputs.len() > 1 {
+        Some("only a single SAM or BAM input is supported".to_string())
+    } else if let Some((_, option)) = fastq_options.iter().find(|(given, _)| *given) {
+        Some(format!("{option} is not supported for SAM or BAM input"))
+    } else if format == Format::Bam
+        && args
+            .compression
+            .compress
+            .is_some_and(|c| c != Compression::Bgzf)
+    {
+        Some("BAM output is always BGZF compressed".to_string())
+    } else {
+        None
+    };
+    if let Some(msg) = unsupported {
+        return Err(input.error(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+    }
+    if format == Format::Bam {
+        let mut output = args
+            .compression
+            .create_as(&args.output[0], Some(Compression::Bgzf))?;
+        let records = bam::fix_bam(&mut input, &mut output, errors, stats)?;
+        output.finish()?;
+        Ok(records)
+    } else {
+        let mut output = args.compression.create(&args.output[0])?;
+        let records = sam::fix_sam(&mut input, &mut output, errors, stats)?;
+        output.finish()?;
+        Ok(records)
+    }
+}
+
+/// Write a JSON value to a file.
+fn write_json(path: &Path, value: &serde_json::Value) -> io::Result<()> {
+    let mut output = Output::create(path, Some(Compression::None), &Default::default())?;
+    serde_json::to_writer_pretty(&mut output, value)?;
+    output.write_all(b"\n")?;
+    output.finish()
+}
+
+/// Demultiplex FASTQ records from the input files (or stdin) into one output file per sample.
+fn run_demux(args: DemuxArgs) -> io::Result<()> {
+    let samples = demux::read_sample_sheet(&args.sample_sheet)?;
+    let demultiplexer = demux::Demultiplexer::new(samples, args.i7_mismatches, args.i5_mismatches)
+        .map_err(|e| files::with_name(e, &args.sample_sheet.display().to_string()))?;
+    std::fs::create_dir_all(&args.outdir)
+        .map_err(|e| files::with_name(e, &args.outdir.display().to_string()))?;
+    let extension = args
+        .compression
+        .compress
+        .unwrap_or(Compression::None)
+        .extension();
+    let output_path = |name: &str| args.outdir.join(format!("{name}.fastq{extension}"));
+
+    // a sample may be listed more than once (e.g. for different lanes) but has a single output
+    let mut output_names: Vec<&str> = Vec::new();
+    let sample_outputs: Vec<usize> = demultiplexer
+        .samples()
+        .iter()
+        .map(
+            |sample| match output_names.iter().position(|&n| n == sample.name) {
+                Some(i) => i,
+                None => {
+                    output_names.push(&sample.name);
+                    output_names.len() - 1
+                }
+            },
+        )
+        .collect();
+    let mut outputs = output_names
+        .iter()
+        .map(|name| args.compression.create(&output_path(name)))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut undetermined = args.compression.create(&output_path(demux::UNDETERMINED))?;
+
+    for path in &args.inputs {
+        let mut input = Input::open(path)?;
+        let location = args.header.location(&mut input)?;
+        demux::demux(
+            &mut input,
+            &demultiplexer,
+            &location,
+            args.rc_i5,
+            &sample_outputs,
+            &mut outputs,
+            &mut undetermined,
+        )?;
+    }
+    outputs.into_iter().try_for_each(Output::finish)?;
+    undetermined.finish()
+}
 
-    output.flush()?;
+/// Reverse-complement the index2 column of a sample sheet, leaving everything else unchanged,
+/// or convert it to a different format.
+fn run_samplesheet(args: SampleSheetArgs) -> io::Result<()> {
+    let mut input = Input::open(&args.input)?;
+    let mut sheet = Vec::new();
+    input.read_to_end(&mut sheet).map_err(|e| input.error(e))?;
+    let message = match args.to {
+        Some(to) => {
+            let (converted, n_samples) =
+                samplesheet::convert(&sheet, to, !args.keep_index2).map_err(|e| input.error(e))?;
+            sheet = converted;
+            format!("Converted {n_samples} samples")
+        }
+        None => {
+            let n_rewritten =
+                samplesheet::reverse_complement_index2(&mut sheet).map_err(|e| input.error(e))?;
+            format!("Reverse-complemented {n_rewritten} index2 values")
+        }
+    };
+    let mut output = Output::create(&args.output, Some(Compression::None), &Default::default())?;
+    output.write_all(&sheet)?;
+    output.finish()?;
+    eprintln!("{message}");
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rstest::rstest;
-
-    #[rstest]
-    #[case::actacttgag(
-        b"@VH00821:6:AACCCKLM5:1:1101:18231:1000 1:N:0:TCTTGAGGTT+ACTACTTGAG\n",
-        b"@VH00821:6:AACCCKLM5:1:1101:18231:1000 1:N:0:TCTTGAGGTT+CTCAAGTAGT\n"
-    )]
-    #[case::a(b"@r1 1:N:0:AAAA+A\n", b"@r1 1:N:0:AAAA+T\n")]
-    #[case::ac(b"@r1 1:N:0:AAAA+AC\n", b"@r1 1:N:0:AAAA+GT\n")]
-    #[case::acg(b"@r1 1:N:0:AAAA+ACG\n", b"@r1 1:N:0:AAAA+CGT\n")]
-    #[case::acgt(b"@r1 1:N:0:AAAA+ACGT\n", b"@r1 1:N:0:AAAA+ACGT\n")]
-    #[case::acgt_lowercase(b"@r2 1:N:0:CCCC+acgt\n", b"@r2 1:N:0:CCCC+acgt\n")]
-    #[case::nnnn(b"@r3 1:N:0:GGGG+NnNn\n", b"@r3 1:N:0:GGGG+nNnN\n")]
-    #[case::actg_mixedcase(b"@r4 1:N:0:TTTT+AcTg\n", b"@r4 1:N:0:TTTT+cAgT\n")]
-    #[case::unknown_chars_are_left_unchanged(b"@r4 1:N:0:TTTT+xyZY\n", b"@r4 1:N:0:TTTT+YZyx\n")]
-    #[case::extra_colons(
-        b"@inst:run:flow:lane:tile:x:y 1:N:0:AAAA+TTTT\n",
-        b"@inst:run:flow:lane:tile:x:y 1:N:0:AAAA+AAAA\n"
-    )]
-    #[case::empty_i5(b"@pyt1 1:N:0:AAAA+\n", b"@pyt1 1:N:0:AAAA+\n")]
-    #[case::single_a(b"@pyt2 1:N:0:AAAA+A\n", b"@pyt2 1:N:0:AAAA+T\n")]
-    #[case::single_n(b"@pyt3 1:N:0:AAAA+N\n", b"@pyt3 1:N:0:AAAA+N\n")]
-    #[case::mixed_case_short(b"@pyt4 1:N:0:AAAA+AaCg\n", b"@pyt4 1:N:0:AAAA+cGtT\n")]
-    #[case::acgtn(b"@pyt5 1:N:0:AAAA+AcgTN\n", b"@pyt5 1:N:0:AAAA+NAcgT\n")]
-    #[case::all_as(b"@pyt6 1:N:0:AAAA+AAAA\n", b"@pyt6 1:N:0:AAAA+TTTT\n")]
-    #[case::all_cs(b"@pyt7 1:N:0:AAAA+CCCC\n", b"@pyt7 1:N:0:AAAA+GGGG\n")]
-    #[case::at_repeat(b"@pyt8 1:N:0:AAAA+ATATAT\n", b"@pyt8 1:N:0:AAAA+ATATAT\n")]
-    #[case::cg_repeat(b"@pyt9 1:N:0:AAAA+CGCGCG\n", b"@pyt9 1:N:0:AAAA+CGCGCG\n")]
-    #[case::ns_flanking(b"@pyt10 1:N:0:AAAA+NNACGTNN\n", b"@pyt10 1:N:0:AAAA+NNACGTNN\n")]
-    #[case::general_atcacg(b"@pyt11 1:N:0:AAAA+ATCACG\n", b"@pyt11 1:N:0:AAAA+CGTGAT\n")]
-    #[case::general_ttaggc(b"@pyt12 1:N:0:AAAA+TTAGGC\n", b"@pyt12 1:N:0:AAAA+GCCTAA\n")]
-    fn rewrite_header_i5_valid(
-        #[case] input: &[u8],
-        #[case] expected: &[u8],
-    ) -> std::io::Result<()> {
-        let mut header = input.to_vec();
-        rewrite_header_i5(&mut header)?;
-        assert_eq!(
-            String::from_utf8_lossy(&header),
-            String::from_utf8_lossy(expected),
-            "\n\n     test input: {}\n    test output: {}\nexpected output: {}\n",
-            String::from_utf8_lossy(input),
-            String::from_utf8_lossy(&header),
-            String::from_utf8_lossy(expected),
-        );
-        // apply again to recover original input
-        rewrite_header_i5(&mut header)?;
-        assert_eq!(
-            String::from_utf8_lossy(&header),
-            String::from_utf8_lossy(input),
+/// Check that the input files (or stdin) can be parsed, printing every problem found to stderr.
+/// Returns an error if any problems were found.
+fn run_check(args: CheckArgs) -> io::Result<()> {
+    let options = ReadOptions {
+        wrapping: if args.wrapped {
+            Wrapping::Unwrap
+        } else {
+            Wrapping::None
+        },
+        ..ReadOptions::default()
+    };
+    let mut total = check::Summary::default();
+    for path in &args.inputs {
+        let mut input = Input::open(path)?;
+        let location = args.header.location(&mut input)?;
+        let name = input.name().to_string();
+        let summary = check::check(
+            &mut input,
+            options,
+            args.strict,
+            &location,
+            |position, e| {
+                eprintln!("{name}: {position}: {e}");
+            },
         );
-        Ok(())
-    }
-
-    #[rstest]
-    #[case::empty_header(b"\n", "'@'")]
-    #[case::no_colon_short(b"@\n", "':'")]
-    #[case::no_colon_long(b"@r6 no_index_here\n", "':'")]
-    #[case::no_plus(b"@r5 1:N:0:AAAA\n", "'+'")]
-    #[case::no_newline(b"@r7 1:N:0:CCCC+AGTC", "newline")]
-    fn rewrite_header_i5_invalid(#[case] input: &[u8], #[case] msg_substr: &str) {
-        let mut header = input.to_vec();
-        let err = rewrite_header_i5(&mut header).expect_err("expected rewrite_header_i5 to fail");
-        let msg = err.to_string();
-        assert!(
-            msg.contains(msg_substr),
-            "error message did not contain expected substring.\n  expected: {msg_substr}\n  got: {msg}"
+        eprintln!(
+            "{name}: checked {} records, found {} problems",
+            summary.records, summary.problems
         );
+        total.records += summary.records;
+        total.problems += summary.problems;
+    }
+    if total.problems > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "found {} problems in {} records",
+                total.problems, total.records
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Check combinations of arguments that clap cannot express.
+fn validate(args: &Args) -> Result<(), clap::Error> {
+    let error =
+        |msg: &str| Err(Cli::command().error(clap::error::ErrorKind::ArgumentConflict, msg));
+    if args.paired {
+        if args.inputs.len() != 2 && args.inputs.len() != 4 {
+            return error("--paired requires 2 or 4 input files");
+        }
+        if args.output.len() != args.inputs.len() {
+            return error("--paired requires one --output per input file");
+        }
+        if args.index_read && args.inputs.len() != 4 {
+            return error(
+                "--index-read with --paired requires 4 input files (R1, R2, I1, I2) and applies to the last one (I2)",
+            );
+        }
+        if args
+            .inputs
+            .iter()
+            .chain(&args.output)
+            .any(|path| path.as_os_str() == files::STDIO)
+        {
+            return error("--paired cannot read from stdin or write to stdout");
+        }
+    } else if args.output.len() > 1 {
+        return error("multiple --output files are only allowed with --paired");
+    }
+    if args.in_place {
+        if args
+            .inputs
+            .iter()
+            .any(|path| path.as_os_str() == files::STDIO)
+        {
+            return error("--in-place cannot patch stdin");
+        }
+        if args.on_error == OnError::Skip {
+            return error(
+                "--in-place cannot skip records, use --on-error passthrough or quarantine",
+            );
+        }
+    }
+    if args.wrapped && !args.unwrap && (args.index_read || args.strict) {
+        return error("--index-read and --strict require --unwrap for wrapped records");
+    }
+    if args.in_place && args.line_endings != LineEndings::Keep {
+        return error("--in-place cannot change the line endings");
+    }
+    if args.in_place && args.header.index_regex.is_some() {
+        // patching the i5 again (to undo or redo a run) would not find the same range
+        return error("--index-regex cannot be used with --in-place");
+    }
+    if args.reject_file.is_some() && args.on_error != OnError::Quarantine {
+        return error("--reject-file is only used with --on-error quarantine");
+    }
+    if args
+        .max_error_fraction
+        .is_some_and(|fraction| !(0.0..=1.0).contains(&fraction))
+    {
+        return error("--max-error-fraction must be between 0 and 1");
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Some(Command::Demux(args)) => run_demux(args),
+        Some(Command::Samplesheet(args)) => run_samplesheet(args),
+        Some(Command::Check(args)) => run_check(args),
+        None => {
+            if let Err(e) = validate(&cli.args) {
+                e.exit();
+            }
+            run(cli.args)
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,231 @@
+use crate::files::Output;
+use std::io::{self, Write};
+
+/// What to do with a record that cannot be rewritten (e.g. a malformed header).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnError {
+    /// Stop with an error at the first invalid record
+    Fail,
+    /// Leave invalid records out of the output
+    Skip,
+    /// Write invalid records to the output unchanged
+    Passthrough,
+    /// Write invalid records unchanged to the reject file
+    Quarantine,
+}
+
+impl OnError {
+    fn past_tense(self) -> &'static str {
+        match self {
+            OnError::Fail => "failed",
+            OnError::Skip => "skipped",
+            OnError::Passthrough => "passed through",
+            OnError::Quarantine => "quarantined",
+        }
+    }
+}
+
+/// Minimum number of records processed before the maximum fraction of invalid records is
+/// checked as they are handled, so that a few invalid records at the start do not exceed it.
+const MIN_RECORDS_FOR_FRACTION: u64 = 1000;
+
+/// Applies an [`OnError`] policy to invalid records, and gives up once there are too many of them.
+pub struct ErrorHandler {
+    on_error: OnError,
+    /// reject file for quarantined records
+    rejects: Option<Output>,
+    max_errors: Option<u64>,
+    max_error_fraction: Option<f64>,
+    errors: u64,
+    /// number of records of the inputs that were processed completely
+    previous_records: u64,
+}
+
+impl ErrorHandler {
+    /// `rejects` is required for the quarantine policy and ignored otherwise.
+    pub fn new(
+        on_error: OnError,
+        rejects: Option<Output>,
+        max_errors: Option<u64>,
+        max_error_fraction: Option<f64>,
+    ) -> Self {
+        ErrorHandler {
+            on_error,
+            rejects,
+            max_errors,
+            max_error_fraction,
+            errors: 0,
+            previous_records: 0,
+        }
+    }
+
+    /// Number of invalid records handled so far.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Count the records of an input that was processed completely, before the next input.
+    pub fn end_input(&mut self, records: u64) {
+        self.previous_records += records;
+    }
+
+    /// Handle an invalid record given as the (unchanged) mates `records` that would have been
+    /// written to `outputs` (divided evenly, so interleaved mates share an output), where
+    /// `record_number` is the number of the record (or set of mates) in the current input.
+    /// Returns `error` for the fail policy, and an error if the maximum number or fraction
+    /// of invalid records is exceeded (the fraction only once enough records were processed).
+    /// Otherwise a warning is printed to stderr.
+    pub fn handle<R: AsRef<[u8]>, W: Write>(
+        &mut self,
+        error: io::Error,
+        record_number: u64,
+        records: &[R],
+        outputs: &mut [W],
+    ) -> io::Result<()> {
+        if self.on_error == OnError::Fail {
+            return Err(error);
+        }
+        self.errors += 1;
+        if let Some(max) = self.max_errors.filter(|&max| self.errors > max) {
+            return Err(io::Error::new(
+                error.kind(),
+                format!("too many invalid records (more than {max}), the last one was: {error}"),
+            ));
+        }
+        let processed = self.previous_records + record_number;
+        if processed >= MIN_RECORDS_FOR_FRACTION {
+            if let Some(e) = self.fraction_exceeded(processed) {
+                return Err(e);
+            }
+        }
+        eprintln!("Warning: {error} ({})", self.on_error.past_tense());
+        match self.on_error {
+            OnError::Fail | OnError::Skip => {}
+            OnError::Passthrough => {
+                let n_outputs = outputs.len();
+                for (i, record) in records.iter().enumerate() {
+                    outputs[i * n_outputs / records.len()].write_all(record.as_ref())?;
+                }
+            }
+            OnError::Quarantine => {
+                let rejects = self
+                    .rejects
+                    .as_mut()
+                    .expect("quarantine policy requires a reject file");
+                for record in records {
+                    rejects.write_all(record.as_ref())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish the reject file, and return an error if more than the maximum fraction
+    /// of the `records` processed were invalid.
+    pub fn finish(mut self, records: u64) -> io::Result<()> {
+        if let Some(rejects) = self.rejects.take() {
+            rejects.finish()?;
+        }
+        if self.errors > 0 {
+            eprintln!(
+                "{} of {records} records were invalid and {}",
+                self.errors,
+                self.on_error.past_tense()
+            );
+        }
+        match self.fraction_exceeded(records) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// The error if more than the maximum fraction of the `records` processed were invalid.
+    fn fraction_exceeded(&self, records: u64) -> Option<io::Error> {
+        let max = self.max_error_fraction?;
+        (self.errors as f64 > max * records as f64).then(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "too many invalid records: {} of {records} is more than the maximum fraction {max}",
+                    self.errors
+                ),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastq::{read_record, Record};
+
+    fn records(text: &[u8]) -> Vec<Record> {
+        let mut record = Record::new();
+        assert!(read_record(&mut &text[..], &mut record).unwrap());
+        vec![record]
+    }
+
+    fn invalid() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "invalid")
+    }
+
+    #[test]
+    fn handle_policies() -> io::Result<()> {
+        let records = records(b"@r1\nACGT\n+\n!!!!\n");
+        let mut outputs = [Vec::new()];
+
+        let mut fail = ErrorHandler::new(OnError::Fail, None, None, None);
+        assert!(fail.handle(invalid(), 1, &records, &mut outputs).is_err());
+
+        let mut skip = ErrorHandler::new(OnError::Skip, None, None, None);
+        skip.handle(invalid(), 1, &records, &mut outputs)?;
+        assert!(outputs[0].is_empty());
+
+        let mut passthrough = ErrorHandler::new(OnError::Passthrough, None, None, None);
+        passthrough.handle(invalid(), 1, &records, &mut outputs)?;
+        assert_eq!(outputs[0], b"@r1\nACGT\n+\n!!!!\n");
+        Ok(())
+    }
+
+    #[test]
+    fn handle_limits() -> io::Result<()> {
+        let records = records(b"@r1\nACGT\n+\n!!!!\n");
+        let mut outputs = [Vec::new()];
+
+        let mut max_errors = ErrorHandler::new(OnError::Skip, None, Some(2), None);
+        max_errors.handle(invalid(), 1, &records, &mut outputs)?;
+        max_errors.handle(invalid(), 1, &records, &mut outputs)?;
+        let msg = max_errors
+            .handle(invalid(), 1, &records, &mut outputs)
+            .unwrap_err()
+            .to_string();
+        assert!(msg.contains("more than 2"), "{msg}");
+
+        let mut max_fraction = ErrorHandler::new(OnError::Skip, None, None, Some(0.1));
+        max_fraction.handle(invalid(), 1, &records, &mut outputs)?;
+        assert!(max_fraction.finish(10).is_ok());
+        let mut max_fraction = ErrorHandler::new(OnError::Skip, None, None, Some(0.1));
+        max_fraction.handle(invalid(), 1, &records, &mut outputs)?;
+        max_fraction.handle(invalid(), 2, &records, &mut outputs)?;
+        assert!(max_fraction.finish(10).is_err());
+
+        // checked as records are handled, once enough records were processed
+        let mut max_fraction = ErrorHandler::new(OnError::Skip, None, None, Some(0.01));
+        for record_number in 1..=5 {
+            max_fraction.handle(invalid(), record_number, &records, &mut outputs)?;
+        }
+        max_fraction.end_input(995);
+        for record_number in 5..=9 {
+            max_fraction.handle(invalid(), record_number, &records, &mut outputs)?;
+        }
+        let msg = max_fraction
+            .handle(invalid(), 10, &records, &mut outputs)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            msg.contains("11 of 1005 is more than the maximum fraction 0.01"),
+            "{msg}"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use crate::demux::Sample;
+use crate::dna::reverse_complement_in_place;
+use crate::header::IndexLocation;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+
+/// Number of records with an index pair, with i5 before and after reverse-complementing.
+struct PairCount<'a> {
+    i7: &'a [u8],
+    i5: Vec<u8>,
+    i5_after: &'a [u8],
+    count: u64,
+}
+
+impl PairCount<'_> {
+    fn to_json(&self) -> Value {
+        json!({
+            "i7": String::from_utf8_lossy(self.i7),
+            "i5_before": String::from_utf8_lossy(&self.i5),
+            "i5_after": String::from_utf8_lossy(self.i5_after),
+            "count": self.count,
+        })
+    }
+}
+
+/// Counts of the records and index pairs seen in the input.
+#[derive(Default)]
+pub struct Stats {
+    /// number of records that were rewritten
+    records: u64,
+    /// number of records read, including the invalid ones
+    total_records: u64,
+    /// number of invalid records (skipped, passed through or quarantined)
+    invalid_records: u64,
+    /// number of records for each index field `<i7>+<i5>` as found in the output headers
+    index_pairs: HashMap<Vec<u8>, u64>,
+}
+
+impl Stats {
+    /// Count a record with this (already rewritten) header, with its index field at `location`.
+    /// Returns an error if the header has no valid index field.
+    pub fn add(&mut self, header: &[u8], location: &IndexLocation) -> io::Result<()> {
+        let (i7, i5) = location.index_field(header)?;
+        if i5.start == i7.end + 1 && header[i7.end] == b'+' {
+            self.add_index_field(&header[i7.start..i5.end]);
+        } else {
+            self.add_pair(&header[i7], &header[i5]);
+        }
+        Ok(())
+    }
+
+    /// Count a record with this (already rewritten) i7 and i5, e.g. from a BC tag.
+    pub fn add_pair(&mut self, i7: &[u8], i5: &[u8]) {
+        self.add_index_field(&[i7, b"+", i5].concat());
+    }
+
+    /// Set the number of records read and the number of those that were invalid,
+    /// which are not counted by [`Stats::add`].
+    pub fn set_totals(&mut self, total_records: u64, invalid_records: u64) {
+        self.total_records = total_records;
+        self.invalid_records = invalid_records;
+    }
+
+    /// Count a record with the index field `<i7>+<i5>`.
+    fn add_index_field(&mut self, pair: &[u8]) {
+        if let Some(count) = self.index_pairs.get_mut(pair) {
+            *count += 1;
+        } else {
+            self.index_pairs.insert(pair.to_vec(), 1);
+        }
+        self.records += 1;
+    }
+
+    /// A JSON report of the statistics. Each index pair is listed with its i5
+    /// both after and (if `rc_i5` is set) before reverse-complementing.
+    /// Pairs that do not match any of the samples (after reverse-complementing i5)
+    /// are unknown, and the `top_n` most frequent of these are listed separately.
+    pub fn to_json(&self, rc_i5: bool, samples: &[Sample], top_n: usize) -> Value {
+        let known: HashSet<(&[u8], &[u8])> = samples
+            .iter()
+            .map(|s| (s.i7.as_slice(), s.i5.as_slice()))
+            .collect();
+
+        let mut pairs: Vec<PairCount> = self
+            .index_pairs
+            .iter()
+            .map(|(pair, &count)| {
+                // keys are always `<i7>+<i5>`
+                let plus = memchr::memchr(b'+', pair).unwrap_or(pair.len());
+                let (i7, i5_after) = (&pair[..plus], &pair[plus + 1..]);
+                let mut i5 = i5_after.to_vec();
+                if rc_i5 {
+                    reverse_complement_in_place(&mut i5);
+                }
+                PairCount {
+                    i7,
+                    i5,
+                    i5_after,
+                    count,
+                }
+            })
+            .collect();
+        // most frequent first, ties in index order for reproducible output
+        pairs.sort_unstable_by(|a, b| (b.count, a.i7, &a.i5).cmp(&(a.count, b.i7, &b.i5)));
+
+        let top_unknown: Vec<Value> = pairs
+            .iter()
+            .filter(|p| {
+                !known.contains(&(
+                    &p.i7.to_ascii_uppercase()[..],
+                    &p.i5_after.to_ascii_uppercase()[..],
+                ))
+            })
+            .take(top_n)
+            .map(PairCount::to_json)
+            .collect();
+
+        let mut i5_lengths: BTreeMap<usize, u64> = BTreeMap::new();
+        for p in &pairs {
+            *i5_lengths.entry(p.i5.len()).or_default() += p.count;
+        }
+
+        json!({
+            "records": self.records,
+            "total_records": self.total_records,
+            "invalid_records": self.invalid_records,
+            "distinct_index_pairs": pairs.len(),
+            "index_pairs": pairs.iter().map(PairCount::to_json).collect::<Vec<_>>(),
+            "top_unknown_index_pairs": top_unknown,
+            "i5_length_histogram": i5_lengths
+                .iter()
+                .map(|(len, count)| (len.to_string(), json!(count)))
+                .collect::<serde_json::Map<_, _>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_to_json() -> io::Result<()> {
+        let mut stats = Stats::default();
+        for header in [
+            &b"@r1 1:N:0:AAAA+CGT\n"[..],
+            b"@r2 1:N:0:CCCC+AACC\n",
+            b"@r3 1:N:0:AAAA+CGT\n",
+            b"@r4 1:N:0:TTTT+CGT\n",
+        ] {
+            stats.add(header, &IndexLocation::default())?;
+        }
+        assert!(stats
+            .add(b"@r5 1:N:0:TTTTACG\n", &IndexLocation::default())
+            .is_err());
+        stats.set_totals(5, 1);
+
+        let samples = [Sample {
+            name: "S1".to_string(),
+            i7: b"AAAA".to_vec(),
+            i5: b"CGT".to_vec(),
+        }];
+        let pair = |i7, i5_before, i5_after, count| json!({"i7": i7, "i5_before": i5_before, "i5_after": i5_after, "count": count});
+        assert_eq!(
+            stats.to_json(true, &samples, 1),
+            json!({
+                "records": 4,
+                "total_records": 5,
+                "invalid_records": 1,
+                "distinct_index_pairs": 3,
+                "index_pairs": [
+                    pair("AAAA", "ACG", "CGT", 2),
+                    pair("CCCC", "GGTT", "AACC", 1),
+                    pair("TTTT", "ACG", "CGT", 1),
+                ],
+                "top_unknown_index_pairs": [pair("CCCC", "GGTT", "AACC", 1)],
+                "i5_length_histogram": {"3": 3, "4": 1},
+            })
+        );
+        // without reverse-complementing, the headers were not changed
+        assert_eq!(
+            stats.to_json(false, &samples, 5)["index_pairs"][0],
+            pair("AAAA", "CGT", "CGT", 2)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use crate::files::{self, Input, Output};
+use crate::policy::ErrorHandler;
+use crate::report::Stats;
+use crate::tags::BarcodeTags;
+use memchr::memchr_iter;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+/// Number of mandatory fields of a SAM alignment line, the optional tags follow them.
+const N_MANDATORY_FIELDS: usize = 11;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Record types of SAM header lines.
+const HEADER_TYPES: [&[u8; 2]; 5] = [b"HD", b"SQ", b"RG", b"PG", b"CO"];
+
+/// Check if the first bytes of an input look like a SAM header line (e.g. `@HD\t`).
+pub fn is_header_line(prefix: &[u8]) -> bool {
+    matches!(prefix, [b'@', a, b, b'\t', ..] if HEADER_TYPES.contains(&&[*a, *b]))
+}
+
+/// Find the values of the barcode tags (`XX:Z:<value>`) of a SAM alignment line.
+/// Returns an error if the line has too few fields, or a barcode tag is not a string.
+fn barcode_tags(line: &[u8]) -> io::Result<BarcodeTags> {
+    let content = line.strip_suffix(b"\n").unwrap_or(line);
+    let content = content.strip_suffix(b"\r").unwrap_or(content);
+    let mut tags = BarcodeTags::default();
+    let mut start = 0;
+    let mut field = 0;
+    for tab in memchr_iter(b'\t', content).chain([content.len()]) {
+        field += 1;
+        if field > N_MANDATORY_FIELDS {
+            let tag = &line[start..tab];
+            if let Some(value) = tags.get_mut(tag.get(..2).unwrap_or(tag)) {
+                if tag.get(2..5) != Some(b":Z:") {
+                    return Err(invalid(format!(
+                        "invalid SAM record: tag {} is not a string",
+                        String::from_utf8_lossy(&tag[..2])
+                    )));
+                }
+                *value = Some(start + 5..tab);
+            }
+        }
+        start = tab + 1;
+    }
+    if field < N_MANDATORY_FIELDS {
+        return Err(invalid(format!(
+            "invalid SAM record: {field} fields instead of at least {N_MANDATORY_FIELDS}"
+        )));
+    }
+    Ok(tags)
+}
+
+/// Reverse-complement the i5 in the barcode tags of a SAM alignment line in place, and reverse
+/// its qualities (see [`BarcodeTags::rewrite_i5`]). Returns the ranges of i7 and i5 in the line,
+/// or an error (leaving the line unchanged) if it cannot be rewritten.
+pub fn fix_line(line: &mut [u8]) -> io::Result<(Range<usize>, Range<usize>)> {
+    barcode_tags(line)?
+        .rewrite_i5(line)
+        .map_err(|e| invalid(format!("invalid SAM record: {e}")))
+}
+
+/// Copy a SAM text input to output, reverse-complementing the i5 in the `BC` (or `B2`) tag
+/// of each alignment line and reversing the corresponding qualities in the `QT` (or `Q2`) tag.
+/// Header lines and all other fields and tags are written unchanged.
+/// Records that cannot be rewritten are passed unchanged to `errors`.
+/// Rewritten records are counted in stats if given.
+/// Returns the number of records (alignment lines) read.
+pub fn fix_sam(
+    input: &mut Input,
+    output: &mut Output,
+    errors: &mut ErrorHandler,
+    mut stats: Option<&mut Stats>,
+) -> io::Result<u64> {
+    let mut line = Vec::new();
+    let mut line_number = 0;
+    let mut records = 0;
+    loop {
+        line.clear();
+        if input
+            .read_until(b'\n', &mut line)
+            .map_err(|e| input.error(e))?
+            == 0
+        {
+            return Ok(records);
+        }
+        line_number += 1;
+        if line.starts_with(b"@") {
+            output.write_all(&line)?;
+            continue;
+        }
+        records += 1;
+        match fix_line(&mut line) {
+            Ok((i7, i5)) => {
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats.add_pair(&line[i7], &line[i5]);
+                }
+                output.write_all(&line)?;
+            }
+            Err(e) => {
+                let position = format!("record {records} (line {line_number})");
+                let e = files::with_name(files::with_name(e, &position), input.name());
+                errors.handle(e, records, &[&line], std::slice::from_mut(output))?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const FIELDS: &str = "r1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII";
+
+    #[rstest]
+    #[case::bc_qt(
+        "\tBC:Z:AAAA-ACGG\tQT:Z:ABCD EFGH\n",
+        "\tBC:Z:AAAA-CCGT\tQT:Z:ABCD HGFE\n"
+    )]
+    #[case::qt_bc_crlf(
+        "\tQT:Z:ABCD EFGH\tBC:Z:AAAA-ACGG\r\n",
+        "\tQT:Z:ABCD HGFE\tBC:Z:AAAA-CCGT\r\n"
+    )]
+    #[case::b2_q2(
+        "\tBC:Z:AAAA\tRG:Z:rg1\tB2:Z:ACGG\tQ2:Z:EFGH",
+        "\tBC:Z:AAAA\tRG:Z:rg1\tB2:Z:CCGT\tQ2:Z:HGFE"
+    )]
+    fn rewrite_tags(#[case] tags: &str, #[case] expected: &str) -> io::Result<()> {
+        let mut line = format!("{FIELDS}{tags}").into_bytes();
+        let (i7, i5) = fix_line(&mut line)?;
+        assert_eq!(
+            String::from_utf8_lossy(&line),
+            format!("{FIELDS}{expected}")
+        );
+        assert_eq!(&line[i7], b"AAAA");
+        assert_eq!(&line[i5], b"CCGT");
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::missing_bc("\tRG:Z:rg1\n", "missing BC (or B2) tag")]
+    #[case::not_a_string("\tBC:i:1\n", "tag BC is not a string")]
+    #[case::no_separator("\tBC:Z:AAAAACGG\n", "missing '-' between i7 and i5")]
+    #[case::qt_length("\tBC:Z:AAAA-ACGG\tQT:Z:ABCD\n", "BC and QT tag lengths differ")]
+    fn invalid_tags(#[case] tags: &str, #[case] error: &str) {
+        let mut line = format!("{FIELDS}{tags}").into_bytes();
+        let msg = fix_line(&mut line).unwrap_err().to_string();
+        assert!(msg.contains(error), "{msg}");
+        assert_eq!(String::from_utf8_lossy(&line), format!("{FIELDS}{tags}"));
+    }
+
+    #[test]
+    fn too_few_fields() {
+        let msg = fix_line(&mut b"r1\t4\t*\n".to_vec())
+            .unwrap_err()
+            .to_string();
+        assert!(msg.contains("3 fields instead of at least 11"), "{msg}");
+    }
+
+    #[rstest]
+    #[case::hd(b"@HD\tVN:1.6", true)]
+    #[case::co(b"@CO\tcomment", true)]
+    #[case::fastq(b"@r1 1:N:0:AAAA+ACGT\n", false)]
+    #[case::fastq_tab_comment(b"@A1\tBC:Z:ACGT+TTGA\n", false)]
+    #[case::short(b"@HD", false)]
+    fn header_line(#[case] prefix: &[u8], #[case] expected: bool) {
+        assert_eq!(is_header_line(prefix), expected);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::dna::reverse_complement_in_place;
+use clap::ValueEnum;
+use std::io;
+use std::ops::Range;
+
+/// Sample sheet formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Illumina SampleSheet.csv (v1 or v2 / BCL Convert)
+    Illumina,
+    /// Element AVITI RunManifest.csv
+    Aviti,
+}
+
+impl Format {
+    /// Sections that contain the table of samples: `[Data]` in v1 and `[BCLConvert_Data]`
+    /// in v2 Illumina sample sheets, and `[SAMPLES]` in AVITI run manifests.
+    const fn data_sections(self) -> &'static [&'static str] {
+        match self {
+            Format::Illumina => &["Data", "BCLConvert_Data"],
+            Format::Aviti => &["SAMPLES"],
+        }
+    }
+
+    /// Names of the sample name, index1, index2 and lane columns (compared case-insensitively).
+    const fn columns(self) -> [&'static str; 4] {
+        match self {
+            Format::Illumina => ["Sample_ID", "Index", "Index2", "Lane"],
+            Format::Aviti => ["SampleName", "Index1", "Index2", "Lane"],
+        }
+    }
+}
+
+/// Trimmed fields of a row of the sample table.
+struct Row {
+    name: Option<Range<usize>>,
+    index1: Option<Range<usize>>,
+    index2: Range<usize>,
+    lane: Option<Range<usize>>,
+}
+
+/// Ranges of the lines of a text, excluding the line ending ('\n' or "\r\n").
+fn lines(text: &[u8]) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut start = 0;
+    std::iter::from_fn(move || {
+        if start >= text.len() {
+            return None;
+        }
+        let end = memchr::memchr(b'\n', &text[start..]).map_or(text.len(), |i| start + i);
+        let line = start..end;
+        start = end + 1;
+        if line.end > line.start && text[line.end - 1] == b'\r' {
+            Some(line.start..line.end - 1)
+        } else {
+            Some(line)
+        }
+    })
+}
+
+/// Ranges of the comma separated fields of a line.
+fn fields(text: &[u8], line: Range<usize>) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut start = line.start;
+    let mut done = false;
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let end = memchr::memchr(b',', &text[start..line.end]).map_or(line.end, |i| start + i);
+        let field = start..end;
+        done = end == line.end;
+        start = end + 1;
+        Some(field)
+    })
+}
+
+/// Narrow a field to its contents without surrounding whitespace or quotes.
+fn trim_field(text: &[u8], mut field: Range<usize>) -> Range<usize> {
+    while field.start < field.end && matches!(text[field.start], b' ' | b'\t' | b'"') {
+        field.start += 1;
+    }
+    while field.end > field.start && matches!(text[field.end - 1], b' ' | b'\t' | b'"') {
+        field.end -= 1;
+    }
+    field
+}
+
+/// The name of the section if the line is a section header such as `[Data],,,`.
+fn section_name(line: &[u8]) -> Option<&[u8]> {
+    let rest = line.strip_prefix(b"[")?;
+    let end = memchr::memchr(b']', rest)?;
+    Some(&rest[..end])
+}
+
+/// Find the table of samples in the data section(s) of a sample sheet.
+/// Returns the format of the sample sheet and the rows of the table, or an error if there
+/// is no data section or it has no index2 column.
+fn data_rows(sheet: &[u8]) -> io::Result<(Format, Vec<Row>)> {
+    let mut rows = Vec::new();
+    let mut sheet_format = None;
+    // format of the current data section, and its columns once its header line has been read
+    let mut section_format = None;
+    let mut columns: Option<[Option<usize>; 4]> = None;
+    for line in lines(sheet) {
+        if let Some(name) = section_name(&sheet[line.clone()]) {
+            section_format = [Format::Illumina, Format::Aviti]
+                .into_iter()
+                .find(|format| format.data_sections().iter().any(|s| s.as_bytes() == name));
+            sheet_format = sheet_format.or(section_format);
+            columns = None;
+            continue;
+        }
+        let Some(format) = section_format else {
+            continue;
+        };
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<Range<usize>> = fields(sheet, line).map(|f| trim_field(sheet, f)).collect();
+        let Some([name, index1, index2, lane]) = columns else {
+            // first line of a data section is the header line
+            let header = format.columns().map(|column| {
+                fields
+                    .iter()
+                    .position(|field| sheet[field.clone()].eq_ignore_ascii_case(column.as_bytes()))
+            });
+            if header[2].is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "sample sheet data section has no {} column",
+                        format.columns()[2]
+                    ),
+                ));
+            }
+            columns = Some(header);
+            continue;
+        };
+        let field = |column: Option<usize>| column.and_then(|c| fields.get(c).cloned());
+        if let Some(index2) = field(index2) {
+            rows.push(Row {
+                name: field(name),
+                index1: field(index1),
+                index2,
+                lane: field(lane),
+            });
+        }
+    }
+    let Some(format) = sheet_format else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "sample sheet has no {} section",
+                [Format::Illumina, Format::Aviti]
+                    .iter()
+                    .flat_map(|format| format.data_sections())
+                    .map(|s| format!("[{s}]"))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ),
+        ));
+    };
+    Ok((format, rows))
+}
+
+/// Reverse-complement the index2 column of the data section(s) of an Illumina sample sheet
+/// or AVITI run manifest in-place. Everything else, including the line endings, is left unchanged.
+/// Returns the number of rewritten index2 values, or an error if there is no data section
+/// or it has no index2 column.
+pub fn reverse_complement_index2(sheet: &mut [u8]) -> io::Result<usize> {
+    let (_, rows) = data_rows(sheet)?;
+    let mut n_rewritten = 0;
+    for row in rows.into_iter().filter(|row| !row.index2.is_empty()) {
+        reverse_complement_in_place(&mut sheet[row.index2]);
+        n_rewritten += 1;
+    }
+    Ok(n_rewritten)
+}
+
+/// Append a row with the given lane, sample name, index1 and index2 to a sample sheet
+/// in the given format. The lane is only included if `with_lane` is set.
+fn push_row(out: &mut Vec<u8>, format: Format, with_lane: bool, [lane, rest @ ..]: [&[u8]; 4]) {
+    let mut fields = rest.to_vec();
+    if with_lane {
+        match format {
+            Format::Illumina => fields.insert(0, lane),
+            Format::Aviti => fields.push(lane),
+        }
+    }
+    out.extend_from_slice(&fields.join(&b',')[..]);
+    out.push(b'\n');
+}
+
+/// Convert the samples of an Illumina sample sheet or AVITI run manifest to a minimal
+/// sample sheet in the given format, optionally reverse-complementing index2.
+/// AVITI samples with multiple lanes (e.g. `1+2`) are listed once per lane in Illumina sample sheets.
+/// Returns the converted sample sheet and the number of samples.
+pub fn convert(sheet: &[u8], to: Format, rc_index2: bool) -> io::Result<(Vec<u8>, usize)> {
+    let (from, rows) = data_rows(sheet)?;
+    let text = |range: &Option<Range<usize>>| -> &[u8] {
+        range.as_ref().map_or(&[][..], |r| &sheet[r.clone()])
+    };
+    let with_lane = rows.iter().any(|row| !text(&row.lane).is_empty());
+
+    let mut out = match to {
+        Format::Illumina => b"[Header]\nFileFormatVersion,2\n[BCLConvert_Data]\n".to_vec(),
+        Format::Aviti => b"[SAMPLES]\n".to_vec(),
+    };
+    let [name, index1, index2, lane] = to.columns().map(str::as_bytes);
+    push_row(&mut out, to, with_lane, [lane, name, index1, index2]);
+
+    let mut n_samples = 0;
+    for row in rows.iter().filter(|row| !text(&row.name).is_empty()) {
+        let mut i5 = sheet[row.index2.clone()].to_vec();
+        if rc_index2 {
+            reverse_complement_in_place(&mut i5);
+        }
+        let lanes: Vec<&[u8]> = match (from, to) {
+            (Format::Aviti, Format::Illumina) => text(&row.lane).split(|&b| b == b'+').collect(),
+            _ => vec![text(&row.lane)],
+        };
+        for lane in lanes {
+            push_row(
+                &mut out,
+                to,
+                with_lane,
+                [lane, text(&row.name), text(&row.index1), &i5],
+            );
+        }
+        n_samples += 1;
+    }
+    Ok((out, n_samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::v1(
+        "[Header],,,,\r\nIEMFileVersion,4,,,\r\n\r\n[Reads],,,,\r\n151,,,,\r\n\r\n[Data],,,,\r\nSample_ID,Sample_Name,index,I5_Index_ID,index2\r\nS1,s1,ACGT,i5a,ACCGTT\r\nS2,s2,TTTT,i5b, gatc \r\n,,,,\r\n",
+        "[Header],,,,\r\nIEMFileVersion,4,,,\r\n\r\n[Reads],,,,\r\n151,,,,\r\n\r\n[Data],,,,\r\nSample_ID,Sample_Name,index,I5_Index_ID,index2\r\nS1,s1,ACGT,i5a,AACGGT\r\nS2,s2,TTTT,i5b, gatc \r\n,,,,\r\n",
+        2
+    )]
+    #[case::v2(
+        "[Header]\nFileFormatVersion,2\n[BCLConvert_Settings]\nAdapterRead1,ACGT\n[BCLConvert_Data]\nLane,Sample_ID,Index,Index2\n1,S1,ACGT,\"AAAC\"\n1,S2,CCCC,GGGA\n[Cloud_Data]\nSample_ID,Index2\nS1,AAAC\n",
+        "[Header]\nFileFormatVersion,2\n[BCLConvert_Settings]\nAdapterRead1,ACGT\n[BCLConvert_Data]\nLane,Sample_ID,Index,Index2\n1,S1,ACGT,\"GTTT\"\n1,S2,CCCC,TCCC\n[Cloud_Data]\nSample_ID,Index2\nS1,AAAC\n",
+        2
+    )]
+    #[case::aviti(
+        "[RUNVALUES]\nKeyName,Value\nrun,1\n[SETTINGS]\nSettingName,Value\nI1Fastq,True\n[SAMPLES]\nSampleName,Index1,Index2,Lane,Project\nS1,ACGT,AACC,1+2,P\nS2,CCCC,TTTA,1,P\n",
+        "[RUNVALUES]\nKeyName,Value\nrun,1\n[SETTINGS]\nSettingName,Value\nI1Fastq,True\n[SAMPLES]\nSampleName,Index1,Index2,Lane,Project\nS1,ACGT,GGTT,1+2,P\nS2,CCCC,TAAA,1,P\n",
+        2
+    )]
+    #[case::short_row_and_no_trailing_newline(
+        "[Data]\nSample_ID,index,index2\nS1,ACGT\nS2,ACGT,AAAC",
+        "[Data]\nSample_ID,index,index2\nS1,ACGT\nS2,ACGT,GTTT",
+        1
+    )]
+    fn reverse_complement_index2_valid(
+        #[case] input: &str,
+        #[case] expected: &str,
+        #[case] expected_count: usize,
+    ) -> io::Result<()> {
+        let mut sheet = input.as_bytes().to_vec();
+        assert_eq!(reverse_complement_index2(&mut sheet)?, expected_count);
+        assert_eq!(String::from_utf8_lossy(&sheet), expected);
+        // apply again to recover original input
+        reverse_complement_index2(&mut sheet)?;
+        assert_eq!(String::from_utf8_lossy(&sheet), input);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::aviti_to_illumina(
+        "[SETTINGS]\nSettingName,Value\n[SAMPLES]\nSampleName,Index1,Index2,Lane,Project\nS1,ACGT,AACC,1+2,P\nS2,CCCC,TTTA,1,P\n",
+        Format::Illumina,
+        "[Header]\nFileFormatVersion,2\n[BCLConvert_Data]\nLane,Sample_ID,Index,Index2\n1,S1,ACGT,GGTT\n2,S1,ACGT,GGTT\n1,S2,CCCC,TAAA\n",
+        2
+    )]
+    #[case::illumina_v1_to_aviti(
+        "[Data],,,\r\nSample_ID,Sample_Name,index,index2\r\nS1,s1,ACGT,AACC\r\nS2,s2,CCCC,TTTA\r\n,,,\r\n",
+        Format::Aviti,
+        "[SAMPLES]\nSampleName,Index1,Index2\nS1,ACGT,GGTT\nS2,CCCC,TAAA\n",
+        2
+    )]
+    #[case::illumina_v2_to_aviti(
+        "[BCLConvert_Data]\nLane,Sample_ID,Index,Index2\n1,S1,ACGT,AACC\n2,S1,ACGT,AACC\n",
+        Format::Aviti,
+        "[SAMPLES]\nSampleName,Index1,Index2,Lane\nS1,ACGT,GGTT,1\nS1,ACGT,GGTT,2\n",
+        2
+    )]
+    fn convert_valid(
+        #[case] input: &str,
+        #[case] to: Format,
+        #[case] expected: &str,
+        #[case] expected_count: usize,
+    ) -> io::Result<()> {
+        let (sheet, n_samples) = convert(input.as_bytes(), to, true)?;
+        assert_eq!(String::from_utf8_lossy(&sheet), expected);
+        assert_eq!(n_samples, expected_count);
+        // converting again to the same format without reverse-complementing index2 changes nothing
+        let (sheet, _) = convert(&sheet, to, false)?;
+        assert_eq!(String::from_utf8_lossy(&sheet), expected);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::no_data_section(
+        "[Header]\nFileFormatVersion,2\n",
+        "no [Data] or [BCLConvert_Data] or [SAMPLES]"
+    )]
+    #[case::single_index("[Data]\nSample_ID,index\nS1,ACGT\n", "no Index2 column")]
+    fn reverse_complement_index2_invalid(#[case] input: &str, #[case] msg_substr: &str) {
+        let mut sheet = input.as_bytes().to_vec();
+        let msg = reverse_complement_index2(&mut sheet)
+            .unwrap_err()
+            .to_string();
+        assert!(msg.contains(msg_substr), "{msg}");
+    }
+}
@@ -0,0 +1,175 @@
+use crate::dna::reverse_complement_in_place;
+use memchr::memrchr2;
+use std::io;
+use std::ops::Range;
+
+/// Locate the i7 and i5 parts of a sample barcode tag value `<i7>-<i5>` (the BC tag of SAM/BAM),
+/// where `+` is also accepted as the separator.
+/// Returns the ranges of i7 and i5 within the value, or an error if there is no separator.
+pub fn barcode_parts(bc: &[u8]) -> io::Result<(Range<usize>, Range<usize>)> {
+    let Some(separator) = memrchr2(b'-', b'+', bc) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "invalid BC tag '{}': missing '-' between i7 and i5",
+                String::from_utf8_lossy(bc)
+            ),
+        ));
+    };
+    Ok((0..separator, separator + 1..bc.len()))
+}
+
+/// Reverse-complement the i5 part of a BC tag value in place, and reverse the corresponding part
+/// of the QT tag value with its qualities if given.
+/// Returns the ranges of i7 and i5 within the BC value, or an error (leaving both values unchanged)
+/// if the BC value has no separator or the QT value has a different length.
+pub fn rewrite_barcode_i5(
+    bc: &mut [u8],
+    qt: Option<&mut [u8]>,
+) -> io::Result<(Range<usize>, Range<usize>)> {
+    let (i7, i5) = barcode_parts(bc)?;
+    if let Some(qt) = qt {
+        check_qualities(bc, qt, "BC and QT")?;
+        qt[i5.clone()].reverse();
+    }
+    reverse_complement_in_place(&mut bc[i5.clone()]);
+    Ok((i7, i5))
+}
+
+/// Check that a quality tag value has the same length as its barcode tag value.
+fn check_qualities(barcode: &[u8], qualities: &[u8], names: &str) -> io::Result<()> {
+    if qualities.len() != barcode.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{names} tag lengths differ: {} and {}",
+                barcode.len(),
+                qualities.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Split the disjoint ranges `a` and (if given) `b` of buf into mutable slices.
+fn split_two(
+    buf: &mut [u8],
+    a: Range<usize>,
+    b: Option<Range<usize>>,
+) -> (&mut [u8], Option<&mut [u8]>) {
+    match b {
+        None => (&mut buf[a], None),
+        Some(b) if b.start >= a.end => {
+            let (before, after) = buf.split_at_mut(b.start);
+            (&mut before[a], Some(&mut after[..b.len()]))
+        }
+        Some(b) => {
+            let (before, after) = buf.split_at_mut(a.start);
+            (&mut after[..a.len()], Some(&mut before[b]))
+        }
+    }
+}
+
+/// Location of the values of the sample barcode tags of a SAM or BAM record:
+/// `BC` (`<i7>-<i5>`) with its qualities in `QT`, or a separate i5 in `B2` with its qualities in `Q2`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BarcodeTags {
+    pub bc: Option<Range<usize>>,
+    pub qt: Option<Range<usize>>,
+    pub b2: Option<Range<usize>>,
+    pub q2: Option<Range<usize>>,
+}
+
+impl BarcodeTags {
+    /// The location of the value of tag `name`, if it is one of the barcode tags.
+    pub fn get_mut(&mut self, name: &[u8]) -> Option<&mut Option<Range<usize>>> {
+        match name {
+            b"BC" => Some(&mut self.bc),
+            b"QT" => Some(&mut self.qt),
+            b"B2" => Some(&mut self.b2),
+            b"Q2" => Some(&mut self.q2),
+            _ => None,
+        }
+    }
+
+    /// Reverse-complement the i5 in `record`, which is the `B2` tag value if there is one,
+    /// otherwise the part of the `BC` tag value after the separator, and reverse its qualities.
+    /// Returns the ranges of i7 (the `BC` value or its part before the separator) and i5
+    /// in record, or an error (leaving the record unchanged) if it cannot be rewritten.
+    pub fn rewrite_i5(&self, record: &mut [u8]) -> io::Result<(Range<usize>, Range<usize>)> {
+        if let Some(b2) = &self.b2 {
+            let (i5, q2) = split_two(record, b2.clone(), self.q2.clone());
+            if let Some(q2) = q2 {
+                check_qualities(i5, q2, "B2 and Q2")?;
+                q2.reverse();
+            }
+            reverse_complement_in_place(i5);
+            let i7 = self.bc.clone().unwrap_or(b2.start..b2.start);
+            return Ok((i7, b2.clone()));
+        }
+        let Some(bc) = &self.bc else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing BC (or B2) tag",
+            ));
+        };
+        let (value, qt) = split_two(record, bc.clone(), self.qt.clone());
+        let (i7, i5) = rewrite_barcode_i5(value, qt)?;
+        let shift = |r: Range<usize>| bc.start + r.start..bc.start + r.end;
+        Ok((shift(i7), shift(i5)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::hyphen(b"AAAA-ACGG", b"ABCD EFGH", b"AAAA-CCGT", b"ABCD HGFE")]
+    #[case::plus(b"AAAA+ACGG", b"ABCD EFGH", b"AAAA+CCGT", b"ABCD HGFE")]
+    #[case::last_separator(b"AA-CC-ACG", b"AB CD EFG", b"AA-CC-CGT", b"AB CD GFE")]
+    #[case::empty_i5(b"AAAA-", b"ABCD ", b"AAAA-", b"ABCD ")]
+    fn rewrite(
+        #[case] bc: &[u8],
+        #[case] qt: &[u8],
+        #[case] expected_bc: &[u8],
+        #[case] expected_qt: &[u8],
+    ) -> io::Result<()> {
+        let (mut bc, mut qt) = (bc.to_vec(), qt.to_vec());
+        rewrite_barcode_i5(&mut bc, Some(&mut qt))?;
+        assert_eq!(bc, expected_bc);
+        assert_eq!(qt, expected_qt);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::no_separator(b"AAAAACGG", b"ABCDEFGH")]
+    #[case::qt_length(b"AAAA-ACGG", b"ABCDEFGH")]
+    fn invalid(#[case] bc: &[u8], #[case] qt: &[u8]) {
+        let (mut bc_copy, mut qt_copy) = (bc.to_vec(), qt.to_vec());
+        assert!(rewrite_barcode_i5(&mut bc_copy, Some(&mut qt_copy)).is_err());
+        assert_eq!(bc_copy, bc);
+        assert_eq!(qt_copy, qt);
+    }
+
+    #[rstest]
+    #[case::bc_qt(b"AAAA-ACGG ABCD EFGH", [Some(0..9), Some(10..19), None, None], b"AAAA-CCGT ABCD HGFE", "CCGT")]
+    #[case::qt_bc(b"ABCD EFGH AAAA-ACGG", [Some(10..19), Some(0..9), None, None], b"ABCD HGFE AAAA-CCGT", "CCGT")]
+    #[case::b2_q2(b"AAAA ACGG EFGH", [Some(0..4), None, Some(5..9), Some(10..14)], b"AAAA CCGT HGFE", "CCGT")]
+    #[case::b2_only(b"ACGG", [None, None, Some(0..4), None], b"CCGT", "CCGT")]
+    fn rewrite_tags(
+        #[case] record: &[u8],
+        #[case] tags: [Option<Range<usize>>; 4],
+        #[case] expected: &[u8],
+        #[case] expected_i5: &str,
+    ) -> io::Result<()> {
+        let [bc, qt, b2, q2] = tags;
+        let tags = BarcodeTags { bc, qt, b2, q2 };
+        let mut record = record.to_vec();
+        let (_, i5) = tags.rewrite_i5(&mut record)?;
+        assert_eq!(record, expected);
+        assert_eq!(String::from_utf8_lossy(&record[i5]), expected_i5);
+        Ok(())
+    }
+}
@@ -83,3 +83,1450 @@ ACGT\n\
         .failure()
         .stderr(predicates::str::contains("truncated"));
 }
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn gunzip(data: &[u8]) -> Vec<u8> {
+    use std::io::Read;
+    let mut decoded = Vec::new();
+    flate2::read::MultiGzDecoder::new(data)
+        .read_to_end(&mut decoded)
+        .unwrap();
+    decoded
+}
+
+#[test]
+fn valid_gzip_input_multi_member() {
+    let r1 = b"@r1 1:N:0:AAAA+ACTACTTGAG\nACGT\n+\n!!!!\n";
+    let r2 = b"@r2 1:N:0:CCCC+atcacg\nTGCA\n+\n####\n";
+    let expected = b"@r1 1:N:0:AAAA+CTCAAGTAGT\nACGT\n+\n!!!!\n\
+@r2 1:N:0:CCCC+cgtgat\nTGCA\n+\n####\n";
+
+    // concatenated gzip members, as produced by e.g. `cat a.gz b.gz`
+    let mut input = gzip(r1);
+    input.extend(gzip(r2));
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(&expected[..]);
+}
+
+#[test]
+fn valid_gzip_output_roundtrip() {
+    let input = b"@r1 1:N:0:AAAA+ACTACTTGAG\nACGT\n+\n!!!!\n".repeat(1000);
+
+    let output = cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--compress", "gzip", "--compression-level", "1"])
+        .write_stdin(input.clone())
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(&output[..2], &[0x1f, 0x8b]);
+
+    // gzip output can be piped straight back in
+    let output = cargo_bin_cmd!("fastq-fix-i5")
+        .args(["-z", "gzip"])
+        .write_stdin(output)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(gunzip(&output), input);
+}
+
+#[test]
+fn invalid_truncated_gzip() {
+    let input = gzip(&b"@r1 1:N:0:AAAA+ACTACTTGAG\nACGT\n+\n!!!!\n".repeat(100));
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .write_stdin(&input[..input.len() - 10])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("truncated gzip input"));
+}
+
+#[test]
+fn valid_bzip2_and_xz_input() {
+    use std::io::Write;
+    let input = b"@r1 1:N:0:AAAA+ACTACTTGAG\nACGT\n+\n!!!!\n";
+    let expected = b"@r1 1:N:0:AAAA+CTCAAGTAGT\nACGT\n+\n!!!!\n";
+
+    let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), Default::default());
+    bzip2.write_all(input).unwrap();
+    let mut xz = liblzma::write::XzEncoder::new(Vec::new(), 6);
+    xz.write_all(input).unwrap();
+    for compressed in [bzip2.finish().unwrap(), xz.finish().unwrap()] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .write_stdin(compressed)
+            .assert()
+            .success()
+            .stdout(&expected[..]);
+    }
+}
+
+#[test]
+fn invalid_bam_or_binary_input() {
+    // BAM is BGZF compressed, the magic bytes are found after decompression.
+    // Only fixing a single input supports BAM
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("check")
+        .write_stdin(gzip(b"BAM\x01\x00\x00\x00\x00"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdin>: input appears to be BAM, not FASTQ",
+        ));
+    cargo_bin_cmd!("fastq-fix-i5")
+        .write_stdin(&b"PK\x03\x04\x14\x00\x00\x00"[..])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdin>: input appears to be compressed or binary, not FASTQ",
+        ));
+}
+
+#[test]
+fn invalid_corrupt_gzip() {
+    let mut input = gzip(&b"@r1 1:N:0:AAAA+ACTACTTGAG\nACGT\n+\n!!!!\n".repeat(100));
+    input[3] = 0xff; // reserved header flag bits
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .write_stdin(input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("invalid gzip input"));
+}
+
+#[test]
+fn valid_input_files_and_output_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = b"@r1 1:N:0:AAAA+ACTACTTGAG\nACGT\n+\n!!!!\n";
+    let r2 = b"@r2 1:N:0:CCCC+atcacg\nTGCA\n+\n####\n";
+    let expected = b"@r1 1:N:0:AAAA+CTCAAGTAGT\nACGT\n+\n!!!!\n\
+@r2 1:N:0:CCCC+cgtgat\nTGCA\n+\n####\n";
+    let in1 = dir.path().join("in1.fastq");
+    let in2 = dir.path().join("in2.fastq.gz");
+    std::fs::write(&in1, r1).unwrap();
+    std::fs::write(&in2, gzip(r2)).unwrap();
+
+    // plain output file
+    let out = dir.path().join("out.fastq");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg(&in1)
+        .arg(&in2)
+        .arg("-o")
+        .arg(&out)
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(std::fs::read(&out).unwrap(), expected);
+
+    // output compression inferred from extension
+    let out = dir.path().join("out.fastq.gz");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg(&in1)
+        .arg(&in2)
+        .arg("--output")
+        .arg(&out)
+        .assert()
+        .success();
+    assert_eq!(gunzip(&std::fs::read(&out).unwrap()), expected);
+
+    // '-' means stdin / stdout
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg(&in1)
+        .arg("-")
+        .args(["-o", "-"])
+        .write_stdin(&r2[..])
+        .assert()
+        .success()
+        .stdout(&expected[..]);
+}
+
+#[test]
+fn invalid_errors_name_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let good = dir.path().join("good.fastq");
+    let bad = dir.path().join("bad.fastq");
+    std::fs::write(&good, b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n").unwrap();
+    std::fs::write(&bad, b"@r1 1:N:0:AAAAACGT\nACGT\n+\n!!!!\n").unwrap();
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg(&good)
+        .arg(&bad)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(format!("{}: ", bad.display())));
+
+    let missing = dir.path().join("missing.fastq");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg(&missing)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(format!(
+            "{}: ",
+            missing.display()
+        )));
+}
+
+#[test]
+fn invalid_output_is_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("x.fastq");
+    let r2 = dir.path().join("r2.fastq");
+    let record = b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n";
+    std::fs::write(&input, record).unwrap();
+    std::fs::write(&r2, record).unwrap();
+    let other = dir.path().join("o.fastq");
+    let same = dir.path().join(".").join("x.fastq");
+
+    for args in [
+        vec!["-o".into(), same.clone()],
+        vec![
+            "--on-error".into(),
+            "quarantine".into(),
+            "--reject-file".into(),
+            same.clone(),
+            "-o".into(),
+            other.clone(),
+        ],
+        vec!["--report".into(), same.clone(), "-o".into(), other.clone()],
+    ] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .arg(&input)
+            .args(args)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(
+                "output file is the same as input",
+            ));
+        assert_eq!(std::fs::read(&input).unwrap(), record);
+    }
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--paired")
+        .arg(&input)
+        .arg(&r2)
+        .arg("-o")
+        .arg(&other)
+        .arg("-o")
+        .arg(&r2)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(format!(
+            "{}: output file is the same as input {}",
+            r2.display(),
+            r2.display()
+        )));
+    assert_eq!(std::fs::read(&r2).unwrap(), record);
+    assert!(!other.exists());
+}
+
+#[test]
+fn valid_paired() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("x_R1_001.fastq");
+    let r2 = dir.path().join("x_R2_001.fastq.gz");
+    std::fs::write(
+        &r1,
+        b"@a 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n@b 1:N:0:AAAA+TTTC\nACGT\n+\n!!!!\n",
+    )
+    .unwrap();
+    std::fs::write(
+        &r2,
+        gzip(b"@a 2:N:0:AAAA+ACGG\nTT\n+\n##\n@b 2:N:0:AAAA+TTTC\nTT\n+\n##\n"),
+    )
+    .unwrap();
+    let o1 = dir.path().join("o1.fastq");
+    let o2 = dir.path().join("o2.fastq.gz");
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--paired")
+        .arg(&r1)
+        .arg(&r2)
+        .arg("-o")
+        .arg(&o1)
+        .arg("-o")
+        .arg(&o2)
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read(&o1).unwrap(),
+        b"@a 1:N:0:AAAA+CCGT\nACGT\n+\n!!!!\n@b 1:N:0:AAAA+GAAA\nACGT\n+\n!!!!\n"
+    );
+    assert_eq!(
+        gunzip(&std::fs::read(&o2).unwrap()),
+        b"@a 2:N:0:AAAA+CCGT\nTT\n+\n##\n@b 2:N:0:AAAA+GAAA\nTT\n+\n##\n"
+    );
+}
+
+#[test]
+fn valid_paired_strict_passthrough() {
+    // record a of R1 is valid, with a separator line that repeats its title, but its mate is not
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    let r2 = dir.path().join("r2.fastq");
+    let o1 = dir.path().join("o1.fastq");
+    let o2 = dir.path().join("o2.fastq");
+    let r1_records =
+        b"@a 1:N:0:AAAA+ACGG\nACGT\n+a 1:N:0:AAAA+ACGG\n!!!!\n@b 1:N:0:AAAA+TTTC\nACGT\n+\n!!!!\n";
+    std::fs::write(&r1, r1_records).unwrap();
+    std::fs::write(
+        &r2,
+        b"@a 2:N:0:AAAA+ACGG\nTT\n+\n#\n@b 2:N:0:AAAA+TTTC\nTT\n+\n##\n",
+    )
+    .unwrap();
+
+    for threads in ["1", "2"] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(["--paired", "--strict", "--on-error", "passthrough", "-t", threads])
+            .arg(&r1)
+            .arg(&r2)
+            .arg("-o")
+            .arg(&o1)
+            .arg("-o")
+            .arg(&o2)
+            .assert()
+            .success()
+            .stderr(predicates::str::contains(
+                "r2.fastq: record 1 (line 1, byte offset 0): invalid FASTQ record: sequence length 2",
+            ));
+        assert_eq!(
+            std::fs::read(&o1).unwrap(),
+            b"@a 1:N:0:AAAA+ACGG\nACGT\n+a 1:N:0:AAAA+ACGG\n!!!!\n@b 1:N:0:AAAA+GAAA\nACGT\n+\n!!!!\n"
+        );
+        assert_eq!(
+            std::fs::read(&o2).unwrap(),
+            b"@a 2:N:0:AAAA+ACGG\nTT\n+\n#\n@b 2:N:0:AAAA+GAAA\nTT\n+\n##\n"
+        );
+    }
+}
+
+#[test]
+fn invalid_paired() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    let r2 = dir.path().join("r2.fastq");
+    let r2_short = dir.path().join("r2_short.fastq");
+    let o1 = dir.path().join("o1.fastq");
+    let o2 = dir.path().join("o2.fastq");
+    std::fs::write(
+        &r1,
+        b"@a 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n@b 1:N:0:AAAA+TTTC\nACGT\n+\n!!!!\n",
+    )
+    .unwrap();
+    std::fs::write(
+        &r2,
+        b"@a 2:N:0:AAAA+ACGG\nTT\n+\n##\n@c 2:N:0:AAAA+TTTC\nTT\n+\n##\n",
+    )
+    .unwrap();
+    std::fs::write(&r2_short, b"@a 2:N:0:AAAA+ACGG\nTT\n+\n##\n").unwrap();
+
+    let paired = |second: &std::path::Path| {
+        let mut cmd = cargo_bin_cmd!("fastq-fix-i5");
+        cmd.arg("-p")
+            .arg(&r1)
+            .arg(second)
+            .arg("-o")
+            .arg(&o1)
+            .arg("-o")
+            .arg(&o2);
+        cmd
+    };
+    paired(&r2)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "read names of paired record 2 differ: 'b'",
+        ));
+    paired(&r2_short)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("different numbers of records"));
+
+    // wrong number of outputs
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--paired")
+        .arg(&r1)
+        .arg(&r2)
+        .arg("-o")
+        .arg(&o1)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("one --output per input"));
+}
+
+#[test]
+fn valid_index_read() {
+    let input = b"@r1 2:N:0:AAAA+ACTACTTGAG\nACTACTTGAG\n+\n!#%')+-/13\n";
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--index-read")
+        .write_stdin(&input[..])
+        .assert()
+        .success()
+        .stdout(&b"@r1 2:N:0:AAAA+CTCAAGTAGT\nCTCAAGTAGT\n+\n31/-+)'%#!\n"[..]);
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--index-read", "--skip-header"])
+        .write_stdin(&input[..])
+        .assert()
+        .success()
+        .stdout(&b"@r1 2:N:0:AAAA+ACTACTTGAG\nCTCAAGTAGT\n+\n31/-+)'%#!\n"[..]);
+}
+
+#[test]
+fn valid_paired_index_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let reads = ["R1", "R2", "I1", "I2"];
+    let mut cmd = cargo_bin_cmd!("fastq-fix-i5");
+    cmd.args(["--paired", "--index-read"]);
+    for read in reads {
+        let path = dir.path().join(format!("{read}.fastq"));
+        std::fs::write(&path, b"@a 1:N:0:AAAA+ACG\nACG\n+\n!#%\n").unwrap();
+        cmd.arg(path);
+    }
+    for read in reads {
+        cmd.arg("-o")
+            .arg(dir.path().join(format!("out_{read}.fastq")));
+    }
+    cmd.assert().success();
+
+    for read in ["R1", "R2", "I1"] {
+        assert_eq!(
+            std::fs::read(dir.path().join(format!("out_{read}.fastq"))).unwrap(),
+            b"@a 1:N:0:AAAA+CGT\nACG\n+\n!#%\n"
+        );
+    }
+    // only the last input is treated as the I2 index read
+    assert_eq!(
+        std::fs::read(dir.path().join("out_I2.fastq")).unwrap(),
+        b"@a 1:N:0:AAAA+CGT\nCGT\n+\n%#!\n"
+    );
+
+    // with only R1 and R2, R2 is not an index read
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--paired", "--index-read"])
+        .arg(dir.path().join("R1.fastq"))
+        .arg(dir.path().join("R2.fastq"))
+        .arg("-o")
+        .arg(dir.path().join("o1.fastq"))
+        .arg("-o")
+        .arg(dir.path().join("o2.fastq"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--index-read with --paired requires 4 input files",
+        ));
+    assert!(!dir.path().join("o2.fastq").exists());
+}
+
+#[test]
+fn valid_demux() {
+    let dir = tempfile::tempdir().unwrap();
+    let sheet = dir.path().join("samples.csv");
+    std::fs::write(
+        &sheet,
+        "sample,i7,i5\nS1,AAAAAA,CCCCCC\nS2,GGGGGG,TTTTTT\nS2,GGGGGG,ACACAC\n",
+    )
+    .unwrap();
+    // i5 orientation in the headers is the reverse-complement of the sample sheet
+    let input = b"@a 1:N:0:AAAAAA+GGGGGG\nA\n+\n!\n\
+@b 1:N:0:GGGGGG+AAAAAA\nC\n+\n!\n\
+@c 1:N:0:GGGGGT+GTGTGT\nG\n+\n!\n\
+@d 1:N:0:CCCCCC+AAAAAA\nT\n+\n!\n\
+@e 1:N:0:AAAAAN+GGGGGN\nN\n+\n!\n";
+    let outdir = dir.path().join("out");
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("demux")
+        .arg("--sample-sheet")
+        .arg(&sheet)
+        .arg("--outdir")
+        .arg(&outdir)
+        .args(["--rc-i5", "-z", "gzip"])
+        .write_stdin(&input[..])
+        .assert()
+        .success();
+
+    let read = |name: &str| gunzip(&std::fs::read(outdir.join(name)).unwrap());
+    assert_eq!(
+        read("S1.fastq.gz"),
+        b"@a 1:N:0:AAAAAA+CCCCCC\nA\n+\n!\n@e 1:N:0:AAAAAN+NCCCCC\nN\n+\n!\n"
+    );
+    assert_eq!(
+        read("S2.fastq.gz"),
+        b"@b 1:N:0:GGGGGG+TTTTTT\nC\n+\n!\n@c 1:N:0:GGGGGT+ACACAC\nG\n+\n!\n"
+    );
+    assert_eq!(
+        read("Undetermined.fastq.gz"),
+        b"@d 1:N:0:CCCCCC+TTTTTT\nT\n+\n!\n"
+    );
+}
+
+#[test]
+fn invalid_demux_ambiguous_samples() {
+    let dir = tempfile::tempdir().unwrap();
+    let sheet = dir.path().join("samples.csv");
+    std::fs::write(&sheet, "sample,i7,i5\nS1,AAAAAA,CCCCCC\nS2,AAAAAA,CCCCGG\n").unwrap();
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("demux")
+        .arg("-s")
+        .arg(&sheet)
+        .arg("-d")
+        .arg(dir.path())
+        .args(["--i5-mismatches", "1"])
+        .write_stdin("")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("'S1' and 'S2' are ambiguous"));
+
+    // fine with no i5 mismatches
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("demux")
+        .arg("-s")
+        .arg(&sheet)
+        .arg("-d")
+        .arg(dir.path())
+        .args(["--i5-mismatches", "0"])
+        .write_stdin("")
+        .assert()
+        .success();
+}
+
+#[test]
+fn valid_samplesheet() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = "[Header]\r\nFileFormatVersion,2\r\n[BCLConvert_Data]\r\nLane,Sample_ID,Index,Index2\r\n1,S1,ACGT,AAAC\r\n";
+    let expected = "[Header]\r\nFileFormatVersion,2\r\n[BCLConvert_Data]\r\nLane,Sample_ID,Index,Index2\r\n1,S1,ACGT,GTTT\r\n";
+    let sheet = dir.path().join("SampleSheet.csv");
+    let out = dir.path().join("SampleSheet_rc.csv");
+    std::fs::write(&sheet, input).unwrap();
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("samplesheet")
+        .arg(&sheet)
+        .arg("-o")
+        .arg(&out)
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("1 index2"));
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), expected);
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("samplesheet")
+        .write_stdin(expected)
+        .assert()
+        .success()
+        .stdout(input);
+}
+
+#[test]
+fn invalid_samplesheet() {
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("samplesheet")
+        .write_stdin("[Data]\nSample_ID,index\nS1,ACGT\n")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdin>: sample sheet data section has no Index2 column",
+        ));
+}
+
+#[test]
+fn valid_samplesheet_aviti_conversion() {
+    let manifest = "[SAMPLES]\nSampleName,Index1,Index2,Lane\nS1,ACGT,AACC,1+2\n";
+    let illumina = "[Header]\nFileFormatVersion,2\n[BCLConvert_Data]\nLane,Sample_ID,Index,Index2\n1,S1,ACGT,GGTT\n2,S1,ACGT,GGTT\n";
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["samplesheet", "--to", "illumina"])
+        .write_stdin(manifest)
+        .assert()
+        .success()
+        .stdout(illumina)
+        .stderr(predicates::str::contains("Converted 1 samples"));
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["samplesheet", "--to", "aviti", "--keep-index2"])
+        .write_stdin(illumina)
+        .assert()
+        .success()
+        .stdout("[SAMPLES]\nSampleName,Index1,Index2,Lane\nS1,ACGT,GGTT,1\nS1,ACGT,GGTT,2\n");
+
+    // without --to the run manifest is rewritten in place
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("samplesheet")
+        .write_stdin(manifest)
+        .assert()
+        .success()
+        .stdout("[SAMPLES]\nSampleName,Index1,Index2,Lane\nS1,ACGT,GGTT,1+2\n");
+}
+
+#[test]
+fn valid_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let report = dir.path().join("report.json");
+    let sheet = dir.path().join("samples.csv");
+    std::fs::write(&sheet, "sample,i7,i5\nS1,AAAA,CCGT\n").unwrap();
+    let input = b"@r1 1:N:0:AAAA+ACGG\nA\n+\n!\n@r2 1:N:0:AAAA+ACGG\nA\n+\n!\n@r3 1:N:0:CCCC+TTTTT\nA\n+\n!\n";
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--report")
+        .arg(&report)
+        .arg("--report-sample-sheet")
+        .arg(&sheet)
+        .write_stdin(&input[..])
+        .assert()
+        .success()
+        .stdout(&b"@r1 1:N:0:AAAA+CCGT\nA\n+\n!\n@r2 1:N:0:AAAA+CCGT\nA\n+\n!\n@r3 1:N:0:CCCC+AAAAA\nA\n+\n!\n"[..]);
+
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&report).unwrap()).unwrap();
+    assert_eq!(report["records"], 3);
+    assert_eq!(report["distinct_index_pairs"], 2);
+    assert_eq!(report["index_pairs"][0]["i5_before"], "ACGG");
+    assert_eq!(report["index_pairs"][0]["i5_after"], "CCGT");
+    assert_eq!(report["index_pairs"][0]["count"], 2);
+    assert_eq!(report["top_unknown_index_pairs"][0]["i7"], "CCCC");
+    assert_eq!(
+        report["top_unknown_index_pairs"].as_array().unwrap().len(),
+        1
+    );
+    assert_eq!(report["i5_length_histogram"]["4"], 2);
+    assert_eq!(report["i5_length_histogram"]["5"], 1);
+    assert_eq!(report["total_records"], 3);
+    assert_eq!(report["invalid_records"], 0);
+
+    // skipped records are only counted in the totals
+    let report_path = dir.path().join("report.json");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "skip", "--report"])
+        .arg(&report_path)
+        .write_stdin([&input[..], b"@r4 1:N:0:AAAAACGG\nA\n+\n!\n"].concat())
+        .assert()
+        .success();
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+    assert_eq!(report["records"], 3);
+    assert_eq!(report["total_records"], 4);
+    assert_eq!(report["invalid_records"], 1);
+}
+
+#[test]
+fn check_valid_and_invalid() {
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("check")
+        .write_stdin(gzip(b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n"))
+        .assert()
+        .success()
+        .stdout("")
+        .stderr(predicates::str::contains(
+            "<stdin>: checked 1 records, found 0 problems",
+        ));
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("check")
+        .write_stdin(
+            b"@r1 1:N:0:AAAAACGT\nACGT\n+\n!!!!\n@r2 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\nr3\nACGT\n",
+        )
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicates::str::contains(
+            "<stdin>: record 1 (line 1, byte offset 0): invalid FASTQ header: missing '+'",
+        ))
+        .stderr(predicates::str::contains(
+            "<stdin>: record 3 (line 9, byte offset 63): truncated FASTQ record",
+        ))
+        .stderr(predicates::str::contains("found 2 problems in 2 records"));
+}
+
+#[test]
+fn invalid_strict() {
+    // mismatched sequence and quality lengths pass through without --strict
+    let input = b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!\n";
+    cargo_bin_cmd!("fastq-fix-i5")
+        .write_stdin(&input[..])
+        .assert()
+        .success();
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--strict")
+        .write_stdin(&input[..])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "sequence length 4 does not match quality length 3",
+        ));
+}
+
+#[test]
+fn valid_strict_repeated_title() {
+    // the i5 in a separator line that repeats the title is rewritten too
+    let input = b"@r1 1:N:0:AAAA+ACGG\nACGT\n+r1 1:N:0:AAAA+ACGG\n!!!!\n";
+    let expected = b"@r1 1:N:0:AAAA+CCGT\nACGT\n+r1 1:N:0:AAAA+CCGT\n!!!!\n";
+    for threads in ["1", "2"] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(["--strict", "-t", threads])
+            .write_stdin(&input[..])
+            .assert()
+            .success()
+            .stdout(&expected[..]);
+    }
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["check", "--strict"])
+        .write_stdin(&expected[..])
+        .assert()
+        .success();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("x.fastq");
+    std::fs::write(&path, input).unwrap();
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--strict", "--in-place"])
+        .arg(&path)
+        .assert()
+        .success();
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+}
+
+#[test]
+fn valid_on_error_policies() {
+    // ACGT is its own reverse complement, so valid records are unchanged
+    let good = b"@r1 1:N:0:AAAA+ACGT\nACGT\n+\n!!!!\n";
+    let bad = b"@r2 1:N:0:AAAAACGT\nACGT\n+\n!!!!\n";
+    let input = [&good[..], bad, good].concat();
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "skip"])
+        .write_stdin(input.clone())
+        .assert()
+        .success()
+        .stdout([&good[..], good].concat())
+        .stderr(predicates::str::contains(
+            "Warning: <stdin>: record 2 (line 5, byte offset 32): invalid FASTQ header",
+        ));
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "passthrough"])
+        .write_stdin(input.clone())
+        .assert()
+        .success()
+        .stdout(input.clone());
+
+    let dir = tempfile::tempdir().unwrap();
+    let rejects = dir.path().join("rejects.fastq");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "quarantine", "--reject-file"])
+        .arg(&rejects)
+        .write_stdin(input.clone())
+        .assert()
+        .success()
+        .stdout([&good[..], good].concat())
+        .stderr(predicates::str::contains(
+            "1 of 3 records were invalid and quarantined",
+        ));
+    assert_eq!(std::fs::read(&rejects).unwrap(), bad);
+
+    // limits on the number or fraction of invalid records
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "skip", "--max-errors", "0"])
+        .write_stdin(input.clone())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("too many invalid records"));
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "skip", "--max-error-fraction", "0.5"])
+        .write_stdin(input.clone())
+        .assert()
+        .success();
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "skip", "--max-error-fraction", "0.2"])
+        .write_stdin(input.clone())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("1 of 3 is more than"));
+    // a mostly invalid input is given up on without reading it to the end
+    let mostly_invalid = [&good[..], &bad.repeat(5000)].concat();
+    for threads in ["1", "2"] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args([
+                "--on-error",
+                "skip",
+                "--max-error-fraction",
+                "0.2",
+                "-t",
+                threads,
+            ])
+            .write_stdin(mostly_invalid.clone())
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(
+                "too many invalid records: 999 of 1000 is more than the maximum fraction 0.2",
+            ));
+    }
+
+    // quarantine requires a reject file
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "quarantine"])
+        .write_stdin(input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("--reject-file"));
+}
+
+#[test]
+fn valid_threads_same_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut r1 = Vec::new();
+    let mut r2 = Vec::new();
+    for i in 0..20000 {
+        let i5 = ["ACGGT", "TTTCA", "GATTACA", "C"][i % 4];
+        let index = if i % 4999 == 4500 { "AAAA" } else { "AAAA+" };
+        r1.extend(format!("@r{i} 1:N:0:{index}{i5}\nACGT\n+\n!!!!\n").bytes());
+        r2.extend(format!("@r{i} 2:N:0:{index}{i5}\nTT\n+\n##\n").bytes());
+    }
+    let in1 = dir.path().join("r1.fastq");
+    let in2 = dir.path().join("r2.fastq");
+    std::fs::write(&in1, &r1).unwrap();
+    std::fs::write(&in2, &r2).unwrap();
+
+    let run = |threads: &str, paired: bool| {
+        let o1 = dir.path().join(format!("o1_{threads}.fastq"));
+        let o2 = dir.path().join(format!("o2_{threads}.fastq"));
+        let mut cmd = cargo_bin_cmd!("fastq-fix-i5");
+        cmd.args(["--on-error", "passthrough", "--threads", threads]);
+        if paired {
+            cmd.arg("--paired").arg(&in1).arg(&in2).arg("-o").arg(&o1);
+        } else {
+            cmd.arg(&in1).arg(&in2);
+        }
+        cmd.arg("-o").arg(&o2).assert().success();
+        let o1 = if paired {
+            std::fs::read(&o1).unwrap()
+        } else {
+            Vec::new()
+        };
+        (o1, std::fs::read(&o2).unwrap())
+    };
+    for paired in [false, true] {
+        let expected = run("1", paired);
+        assert_eq!(run("4", paired), expected);
+        assert_eq!(run("2", paired), expected);
+    }
+
+    // the first invalid record is reported in any case
+    for threads in ["1", "3"] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(["--threads", threads])
+            .arg(&in1)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(
+                "record 4501 (line 18001, byte offset",
+            ));
+    }
+}
+
+#[test]
+fn valid_in_place() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("x.fastq");
+    let journal = dir.path().join("x.fastq.fix-i5-journal");
+    let record = |name: &str, i5: &str| format!("@{name} 1:N:0:AAAA+{i5}\nACGT\n+\n!!!!\n");
+    let original = [
+        record("a", "ACGG"),
+        record("b", "ACGG"),
+        record("c", "TTTC"),
+    ]
+    .concat();
+    let fixed = [
+        record("a", "CCGT"),
+        record("b", "CCGT"),
+        record("c", "GAAA"),
+    ]
+    .concat();
+    let in_place = || {
+        let mut cmd = cargo_bin_cmd!("fastq-fix-i5");
+        cmd.arg("--in-place").arg(&path);
+        cmd
+    };
+
+    std::fs::write(&path, &original).unwrap();
+    in_place().assert().success().stdout("");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), fixed);
+    assert!(!journal.exists());
+    // patching again restores the original
+    in_place().assert().success();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+
+    // a run interrupted after committing record a, while writing the patch of record b
+    let interrupted = [
+        record("a", "CCGT"),
+        record("b", "CCGT"),
+        record("c", "TTTC"),
+    ]
+    .concat();
+    let interrupted_journal = "fastq-fix-i5 in-place journal v1\nsize 93\nend 93\nundo 0\n\
+committed 31 2 5\npending 45 41434747\n";
+    std::fs::write(&path, &interrupted).unwrap();
+    std::fs::write(&journal, interrupted_journal).unwrap();
+    in_place()
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "resuming the interrupted run at record 2",
+        ));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), fixed);
+    assert!(!journal.exists());
+
+    std::fs::write(&path, &interrupted).unwrap();
+    std::fs::write(&journal, interrupted_journal).unwrap();
+    in_place().arg("--undo").assert().success();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+    assert!(!journal.exists());
+    in_place()
+        .arg("--undo")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("no interrupted run to undo"));
+
+    // a failed run that did not patch anything leaves no journal
+    std::fs::write(
+        &path,
+        [record("a", "ACGG"), "@b\nACGT\n+\n!!!!\n".into()].concat(),
+    )
+    .unwrap();
+    in_place()
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("record 2"));
+    assert!(!journal.exists());
+
+    let gz = dir.path().join("x.fastq.gz");
+    std::fs::write(&gz, gzip(original.as_bytes())).unwrap();
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--in-place")
+        .arg(&gz)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("cannot patch a compressed file"));
+}
+
+#[test]
+fn valid_bgzf_output_with_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = b"@r1 1:N:0:AAAA+ACTACTTGAG\nACGT\n+\n!!!!\n".repeat(5000);
+    let expected = b"@r1 1:N:0:AAAA+CTCAAGTAGT\nACGT\n+\n!!!!\n".repeat(5000);
+    let out = dir.path().join("out.fastq.gz");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["-z", "bgzf", "--gzi", "--compression-threads", "2", "-o"])
+        .arg(&out)
+        .write_stdin(input.clone())
+        .assert()
+        .success();
+    let compressed = std::fs::read(&out).unwrap();
+    assert_eq!(gunzip(&compressed), expected);
+    // 190000 bytes are written in 3 blocks, followed by the empty end-of-file block
+    assert_eq!(compressed[..4], [0x1f, 0x8b, 8, 4]);
+    assert_eq!(compressed[12..14], *b"BC");
+    let index = std::fs::read(dir.path().join("out.fastq.gz.gzi")).unwrap();
+    assert_eq!(index.len(), 8 + 3 * 16);
+    assert_eq!(index[..8], 3u64.to_le_bytes());
+    assert_eq!(
+        index[index.len() - 16..index.len() - 8],
+        (compressed.len() as u64 - 28).to_le_bytes()
+    );
+    assert_eq!(index[index.len() - 8..], 190000u64.to_le_bytes());
+
+    // compression is inferred from the .bgz extension, an index requires BGZF
+    let bgz = dir.path().join("out.fastq.bgz");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("-o")
+        .arg(&bgz)
+        .write_stdin(input.clone())
+        .assert()
+        .success();
+    assert_eq!(std::fs::read(&bgz).unwrap()[12..14], *b"BC");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--gzi")
+        .write_stdin(input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "a .gzi index can only be written for a BGZF compressed output file",
+        ));
+}
+
+#[test]
+fn valid_zstd_input_and_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = b"@r1 1:N:0:AAAA+ACTACTTGAG\nACGT\n+\n!!!!\n".repeat(1000);
+    let expected = b"@r1 1:N:0:AAAA+CTCAAGTAGT\nACGT\n+\n!!!!\n".repeat(1000);
+
+    // compression is inferred from the .zst extension
+    let out = dir.path().join("out.fastq.zst");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--compression-level", "19", "--zstd-long", "-o"])
+        .arg(&out)
+        .write_stdin(zstd::encode_all(&input[..], 3).unwrap())
+        .assert()
+        .success();
+    let compressed = std::fs::read(&out).unwrap();
+    assert_eq!(compressed[..4], [0x28, 0xb5, 0x2f, 0xfd]);
+    assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), expected);
+
+    // the bare flag does not take the input that follows it as its value
+    let plain = dir.path().join("in.fastq");
+    std::fs::write(&plain, &input).unwrap();
+    let long = dir.path().join("long.fastq.zst");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--zstd-long")
+        .arg(&plain)
+        .arg("-o")
+        .arg(&long)
+        .assert()
+        .success();
+    assert_eq!(
+        zstd::decode_all(&std::fs::read(&long).unwrap()[..]).unwrap(),
+        expected
+    );
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--zstd-long=24")
+        .arg(&plain)
+        .arg("-o")
+        .arg(&long)
+        .assert()
+        .success();
+
+    // zstd output can be piped straight back in, and the fix undone
+    let output = cargo_bin_cmd!("fastq-fix-i5")
+        .args(["-z", "zstd", "--compression-threads", "2"])
+        .arg(&out)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(zstd::decode_all(&output[..]).unwrap(), input);
+
+    // zstd options are rejected for other output
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["-z", "gzip", "--zstd-long"])
+        .write_stdin(input.clone())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdout>: zstd long distance matching can only be used for zstd compressed output",
+        ));
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--compression-threads", "2"])
+        .write_stdin(input.clone())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "multiple compression threads can only be used for BGZF or zstd compressed output",
+        ));
+
+    // levels above 9 are only supported by zstd
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["-z", "gzip", "--compression-level", "19"])
+        .write_stdin(input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "compression level 19 is not supported for gzip and BGZF output (0-9)",
+        ));
+}
+
+/// An unaligned BAM file (uncompressed) with one record per set of (binary encoded) tags.
+fn ubam(tags: &[&[u8]]) -> Vec<u8> {
+    let text = b"@HD\tVN:1.6\tSO:unsorted\n";
+    let mut bam = b"BAM\x01".to_vec();
+    bam.extend((text.len() as u32).to_le_bytes());
+    bam.extend(text);
+    bam.extend(0u32.to_le_bytes());
+    for (i, tags) in tags.iter().enumerate() {
+        let mut data = Vec::new();
+        data.extend((-1i32).to_le_bytes()); // refID
+        data.extend((-1i32).to_le_bytes()); // pos
+        data.extend([3, 0]); // l_read_name, mapq
+        data.extend(4680u16.to_le_bytes()); // bin
+        data.extend(0u16.to_le_bytes()); // n_cigar_op
+        data.extend(4u16.to_le_bytes()); // flag: unmapped
+        data.extend(4u32.to_le_bytes()); // l_seq
+        data.extend((-1i32).to_le_bytes()); // next_refID
+        data.extend((-1i32).to_le_bytes()); // next_pos
+        data.extend(0i32.to_le_bytes()); // tlen
+        data.extend(format!("r{}\0", i + 1).as_bytes());
+        data.extend([0x12, 0x48]); // ACGT
+        data.extend([30; 4]);
+        data.extend(*tags);
+        bam.extend((data.len() as u32).to_le_bytes());
+        bam.extend(data);
+    }
+    bam
+}
+
+#[test]
+fn valid_ubam() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = ubam(&[
+        b"RGZA\0BCZTCTTGAGGTT-ACTACTTGAG\0QTZABCDEFGHIJ KLMNOPQRST\0",
+        b"BCZAAAA-CCCC\0XiI\x07\0\0\0",
+        b"RGZA\0",
+    ]);
+    let expected = ubam(&[
+        b"RGZA\0BCZTCTTGAGGTT-CTCAAGTAGT\0QTZABCDEFGHIJ TSRQPONMLK\0",
+        b"BCZAAAA-GGGG\0XiI\x07\0\0\0",
+        b"RGZA\0",
+    ]);
+    let out = dir.path().join("out.bam");
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "passthrough", "-o"])
+        .arg(&out)
+        .write_stdin(gzip(&input))
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "<stdin>: record 3 ('r3'): invalid BAM record: missing BC (or B2) tag (passed through)",
+        ));
+    // the output is BGZF compressed, whatever the file extension
+    let compressed = std::fs::read(&out).unwrap();
+    assert_eq!(compressed[12..14], *b"BC");
+    assert_eq!(gunzip(&compressed), expected);
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["-z", "gzip"])
+        .write_stdin(gzip(&input))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "BAM output is always BGZF compressed",
+        ));
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--strict", "-t", "4"])
+        .write_stdin(gzip(&input))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdin>: --strict is not supported for SAM or BAM input",
+        ));
+}
+
+#[test]
+fn valid_sam() {
+    let input = "@HD\tVN:1.6\tSO:unsorted\n\
+@RG\tID:A\tBC:TCTTGAGGTT-ACTACTTGAG\n\
+r1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\tRG:Z:A\tBC:Z:TCTTGAGGTT-ACTACTTGAG\tQT:Z:ABCDEFGHIJ KLMNOPQRST\n\
+r2\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\tBC:Z:AAAA\tB2:Z:ACGG\tQ2:Z:EFGH\n";
+    let expected = "@HD\tVN:1.6\tSO:unsorted\n\
+@RG\tID:A\tBC:TCTTGAGGTT-ACTACTTGAG\n\
+r1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\tRG:Z:A\tBC:Z:TCTTGAGGTT-CTCAAGTAGT\tQT:Z:ABCDEFGHIJ TSRQPONMLK\n\
+r2\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\tBC:Z:AAAA\tB2:Z:CCGT\tQ2:Z:HGFE\n";
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(expected);
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .write_stdin(format!("{input}r3\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdin>: record 3 (line 5): invalid SAM record: missing BC (or B2) tag",
+        ));
+
+    // options that only apply to FASTQ input are rejected
+    for (args, option) in [
+        (&["--index-read"][..], "--index-read"),
+        (&["--interleaved"], "--interleaved"),
+        (&["--strict"], "--strict"),
+        (&["--wrapped", "--unwrap"], "--wrapped"),
+        (&["--line-endings", "lf"], "--line-endings"),
+        (&["-t", "2"], "--threads"),
+        (&["--header-format", "mgi"], "--header-format"),
+        (&["--index-field", "1"], "--index-field"),
+        (&["--index-tag", "BC"], "--index-tag"),
+        (&["--index-regex", "(?<i5>[ACGT]+)"], "--index-regex"),
+    ] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(args)
+            .write_stdin(input)
+            .assert()
+            .failure()
+            .stdout("")
+            .stderr(predicates::str::contains(format!(
+                "<stdin>: {option} is not supported for SAM or BAM input"
+            )));
+    }
+}
+
+#[test]
+fn valid_interleaved() {
+    let input = b"@a 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n@a 2:N:0:AAAA+ACGG\nTT\n+\n##\n\
+@b 1:N:0:AAAA+TTTC\nACGT\n+\n!!!!\n@b 2:N:0:AAAA+TTTG\nTT\n+\n##\n\
+@c 1:N:0:AAAA+TTTC\nACGT\n+\n!!!!\n@c 2:N:0:AAAA+TTTC\nTT\n+\n##\n";
+    let expected = b"@a 1:N:0:AAAA+CCGT\nACGT\n+\n!!!!\n@a 2:N:0:AAAA+CCGT\nTT\n+\n##\n\
+@b 1:N:0:AAAA+TTTC\nACGT\n+\n!!!!\n@b 2:N:0:AAAA+TTTG\nTT\n+\n##\n\
+@c 1:N:0:AAAA+GAAA\nACGT\n+\n!!!!\n@c 2:N:0:AAAA+GAAA\nTT\n+\n##\n";
+
+    for threads in ["1", "2"] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(["--interleaved", "--on-error", "passthrough", "-t", threads])
+            .write_stdin(&input[..])
+            .assert()
+            .success()
+            .stdout(&expected[..])
+            .stderr(predicates::str::contains(
+                "<stdin>: record 4 (line 13, byte offset 89): i5 of mates differ: 'TTTC' and 'TTTG' (passed through)",
+            ))
+            .stderr(predicates::str::contains(
+                "1 of 3 records were invalid and passed through",
+            ));
+    }
+}
+
+#[test]
+fn invalid_interleaved() {
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--interleaved")
+        .write_stdin(
+            &b"@a 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n@a 2:N:0:AAAA+ACGG\nTT\n+\n##\n\
+@b 1:N:0:AAAA+TTTC\nACGT\n+\n!!!!\n@c 2:N:0:AAAA+TTTC\nTT\n+\n##\n"[..],
+        )
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "read names of paired record 2 differ: 'b' in <stdin> but 'c' in <stdin>",
+        ));
+    cargo_bin_cmd!("fastq-fix-i5")
+        .arg("--interleaved")
+        .write_stdin(
+            &b"@a 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n@a 2:N:0:AAAA+ACGG\nTT\n+\n##\n\
+@b 1:N:0:AAAA+TTTC\nACGT\n+\n!!!!\n"[..],
+        )
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdin>: missing mate of interleaved record 2: odd number of records",
+        ));
+}
+
+#[test]
+fn valid_wrapped() {
+    let input = b"@a 1:N:0:AAAA+ACGG\nACG\nTA\n+\n@!!\n!#\n@b 1:N:0:AAAA+TTTC\nAC\n+\n+!\n";
+    let wrapped = b"@a 1:N:0:AAAA+CCGT\nACG\nTA\n+\n@!!\n!#\n@b 1:N:0:AAAA+GAAA\nAC\n+\n+!\n";
+    let unwrapped = b"@a 1:N:0:AAAA+CCGT\nACGTA\n+\n@!!!#\n@b 1:N:0:AAAA+GAAA\nAC\n+\n+!\n";
+
+    for threads in ["1", "2"] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(["--wrapped", "-t", threads])
+            .write_stdin(&input[..])
+            .assert()
+            .success()
+            .stdout(&wrapped[..]);
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(["--wrapped", "--unwrap", "--strict", "-t", threads])
+            .write_stdin(&input[..])
+            .assert()
+            .success()
+            .stdout(&unwrapped[..]);
+    }
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--wrapped"])
+        .write_stdin(&b"@a 1:N:0:AAAA+ACGG\nACG\nTA\n+\n@!!\n!#\n@b 1:N:0:AAAA+TTTC\nAC\n+\n+!!\n"[..])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdin>: record 2 (line 7, byte offset 35): invalid FASTQ record: quality length 3 is longer than sequence length 2",
+        ));
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--wrapped", "--strict"])
+        .write_stdin(&input[..])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("require --unwrap"));
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["check", "--wrapped", "--strict"])
+        .write_stdin(&input[..])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "checked 2 records, found 0 problems",
+        ));
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["check", "--wrapped"])
+        .write_stdin(&b"@a 1:N:0:AAAA+ACGG\nACG\nTA\n+\n@!!\n!#\n@b 1:N:0:AAAA+TTTC\nAC\n+\n+!!\n"[..])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdin>: record 2 (line 7, byte offset 35): invalid FASTQ record: quality length 3 is longer than sequence length 2",
+        ));
+}
+
+#[test]
+fn valid_crlf() {
+    let input =
+        b"@a 1:N:0:AAAA+ACGG\r\nACGT\r\n+\r\n!!!#\r\n@b 1:N:0:AAAA+TTTC\r\nAC\r\n+\r\n+!\r\n";
+    let crlf =
+        b"@a 1:N:0:AAAA+CCGT\r\nACGT\r\n+\r\n!!!#\r\n@b 1:N:0:AAAA+GAAA\r\nAC\r\n+\r\n+!\r\n";
+    let lf = b"@a 1:N:0:AAAA+CCGT\nACGT\n+\n!!!#\n@b 1:N:0:AAAA+GAAA\nAC\n+\n+!\n";
+    let index_read =
+        b"@a 1:N:0:AAAA+CCGT\r\nACGT\r\n+\r\n#!!!\r\n@b 1:N:0:AAAA+GAAA\r\nGT\r\n+\r\n!+\r\n";
+
+    for threads in ["1", "2"] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(["--strict", "-t", threads])
+            .write_stdin(&input[..])
+            .assert()
+            .success()
+            .stdout(&crlf[..]);
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(["--line-endings", "lf", "-t", threads])
+            .write_stdin(&input[..])
+            .assert()
+            .success()
+            .stdout(&lf[..]);
+    }
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--index-read"])
+        .write_stdin(&input[..])
+        .assert()
+        .success()
+        .stdout(&index_read[..]);
+}
+
+#[test]
+fn valid_index_location() {
+    for (args, input, expected) in [
+        (
+            ["--index-field", "1"],
+            "@a 1:N:0:AAAA+ACGG BX:Z:ACGT-1\nACGT\n+\n!!!!\n",
+            "@a 1:N:0:AAAA+CCGT BX:Z:ACGT-1\nACGT\n+\n!!!!\n",
+        ),
+        (
+            ["--index-tag", "BC"],
+            "@a\tBC:Z:AAAA-ACGG\tRX:Z:TTTG\nACGT\n+\n!!!!\n",
+            "@a\tBC:Z:AAAA-CCGT\tRX:Z:TTTG\nACGT\n+\n!!!!\n",
+        ),
+        (
+            ["--index-regex", "#(?<i7>[ACGT]+)\\+(?<i5>[ACGT]+)/"],
+            "@a#AAAA+ACGG/1 x\nACGT\n+\n!!!!\n",
+            "@a#AAAA+CCGT/1 x\nACGT\n+\n!!!!\n",
+        ),
+    ] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(args)
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--index-tag", "BC"])
+        .write_stdin("@a 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "<stdin>: record 1 (line 1, byte offset 0): invalid FASTQ header: missing BC tag",
+        ));
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--index-regex", "#(?<i7>[ACGT]+)"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("no named group 'i5'"));
+
+    // the rewritten i5 of the first mate no longer matches, but it is left unchanged
+    let input = "@a 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n@a 2:N:0:AAAA+TTGG\nACGT\n+\n!!!!\n";
+    for threads in ["1", "2"] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .args(["--interleaved", "--on-error", "passthrough", "-t", threads])
+            .args(["--index-regex", "\\+(?<i5>AC[ACGT]+)"])
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout(input)
+            .stderr(predicates::str::contains(
+                "<stdin>: record 2 (line 5, byte offset 31): invalid FASTQ header: does not match",
+            ));
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("x.fastq");
+    std::fs::write(&path, input).unwrap();
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--in-place", "--index-regex", "\\+(?<i5>AC[ACGT]+)"])
+        .arg(&path)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--index-regex cannot be used with --in-place",
+        ));
+}
+
+#[test]
+fn valid_casava_1_7() {
+    let input = "@HWUSI-EAS100R:6:73:941:1973#ACGT+ACGG/1\nACGT\n+\n!!!!\n\
+@HWUSI-EAS100R:6:73:941:1974#ACGT/1\nACGT\n+\n!!!!\n";
+    let expected = "@HWUSI-EAS100R:6:73:941:1973#ACGT+CCGT/1\nACGT\n+\n!!!!\n\
+@HWUSI-EAS100R:6:73:941:1974#ACGT/1\nACGT\n+\n!!!!\n";
+    cargo_bin_cmd!("fastq-fix-i5")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(expected);
+}
+
+#[test]
+fn valid_header_formats() {
+    let mgi = "@V350000000L1C001R0010000000/1 ACGT+ACGG\nACGT\n+\n!!!!\n";
+    let mgi_fixed = "@V350000000L1C001R0010000000/1 ACGT+CCGT\nACGT\n+\n!!!!\n";
+    let ultima = "@014214_1-Z0001-0123456789\tBC:Z:ACGT-ACGG\nACGT\n+\n!!!!\n";
+    let ultima_fixed = "@014214_1-Z0001-0123456789\tBC:Z:ACGT-CCGT\nACGT\n+\n!!!!\n";
+
+    // detected from the first record
+    for (input, expected) in [(mgi, mgi_fixed), (ultima, ultima_fixed)] {
+        cargo_bin_cmd!("fastq-fix-i5")
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--header-format", "mgi"])
+        .write_stdin(mgi)
+        .assert()
+        .success()
+        .stdout(mgi_fixed);
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--header-format", "illumina"])
+        .write_stdin(ultima)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("missing '+' in index field"));
+
+    // a first record that matches no format is handled like any other invalid record
+    let valid = "@r2 1:N:0:AAAA+ACGG\nACGT\n+\n!!!!\n";
+    cargo_bin_cmd!("fastq-fix-i5")
+        .args(["--on-error", "skip"])
+        .write_stdin(["@r1 1:N:0:ACGT\nACGT\n+\n!!!!\n", valid].concat())
+        .assert()
+        .success()
+        .stdout("@r2 1:N:0:AAAA+CCGT\nACGT\n+\n!!!!\n")
+        .stderr(predicates::str::contains(
+            "<stdin>: record 1 (line 1, byte offset 0): invalid FASTQ header: missing '+'",
+        ));
+}